@@ -1,5 +1,10 @@
+use crate::bignum::Natural;
+
 pub type Num = u64;
 
+/// Number literal used as a value, which unlike sizes and indices may exceed u64.
+pub type Literal = Natural;
+
 pub type SourceIdent = (String, usize);
 
 #[derive(Debug, Clone)]
@@ -11,7 +16,7 @@ pub enum Identifier {
 
 #[derive(Debug, Clone)]
 pub enum Value {
-    Num(Num),
+    Num(Literal),
     Id(Identifier),
 }
 #[derive(Debug, Clone)]
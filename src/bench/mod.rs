@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::diagnostics::SourceFile;
+use crate::driver;
+use crate::emitter::Options;
+use crate::vm;
+
+/// Settings of a benchmark run chosen on the command line.
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    pub directory: String,
+    pub baseline: String,
+    /// Largest accepted increase of cycles over the baseline, in percent.
+    pub tolerance: f64,
+    /// Write the measured costs as the new baseline instead of comparing.
+    pub update: bool,
+    pub compiler: Options,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        BenchOptions {
+            directory: "examples2023".to_string(),
+            baseline: "benchmarks/baseline.txt".to_string(),
+            tolerance: 5.0,
+            update: false,
+            compiler: Options::default(),
+        }
+    }
+}
+
+/// Cost of one compiled program: VM cycles including input and output, and the number of
+/// emitted instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Measurement {
+    cycles: u64,
+    instructions: u64,
+}
+
+/// Reads `# ? value` lines from the header of an example. A program reading more values than
+/// listed fails to run rather than being measured on invented input.
+fn inputs(text: &str) -> Vec<String> {
+    text.lines()
+        .filter_map(|line| line.strip_prefix("# ?"))
+        .map(|value| value.trim().to_string())
+        .collect()
+}
+
+fn measure(path: &Path, options: Options) -> Result<Measurement, String> {
+    let text = fs::read_to_string(path).map_err(|error| error.to_string())?;
+    let path_name = path.display().to_string();
+    let source = SourceFile::new(&path_name, &text);
+    // Warnings are left out to keep the table readable, errors explain a failed measurement.
+    let compilation = driver::compile(&source, options);
+    let Some(assembly) = compilation.assembly else {
+        compilation.errors.iter().for_each(|error| eprint!("{}", error));
+        return Err("compilation failed".to_string());
+    };
+    let program = vm::parse(&assembly).map_err(|error| error.to_string())?;
+    let input = inputs(&text).join("\n");
+    let cost = vm::run::<i64, _, _>(&program, input.as_bytes(), io::sink()).map_err(|error| match error {
+        vm::VmError::EndOfInput => "reads more input than its `# ?` header lists".to_string(),
+        error => error.to_string(),
+    })?;
+    Ok(Measurement { cycles: cost.total, instructions: program.len() as u64 })
+}
+
+/// Baseline file: one `name cycles instructions` line per program, `#` starts a comment.
+fn read_baseline(path: &str) -> Result<BTreeMap<String, Measurement>, String> {
+    let text = fs::read_to_string(path).map_err(|error| format!("cannot read baseline {}: {}", path, error))?;
+    let mut baseline = BTreeMap::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let parsed = match fields[..] {
+            [name, cycles, instructions] => cycles.parse().ok()
+                .zip(instructions.parse().ok())
+                .map(|(cycles, instructions)| (name.to_string(), Measurement { cycles, instructions })),
+            _ => None,
+        };
+        let (name, measurement) = parsed.ok_or(format!("{}:{}: expected `name cycles instructions`", path, number + 1))?;
+        baseline.insert(name, measurement);
+    }
+    Ok(baseline)
+}
+
+fn write_baseline(path: &str, measurements: &BTreeMap<String, Measurement>) -> Result<(), String> {
+    let mut text = String::from("# program cycles instructions\n");
+    for (name, measurement) in measurements {
+        text.push_str(&format!("{} {} {}\n", name, measurement.cycles, measurement.instructions));
+    }
+    if let Some(directory) = Path::new(path).parent() {
+        fs::create_dir_all(directory).map_err(|error| error.to_string())?;
+    }
+    fs::write(path, text).map_err(|error| format!("cannot write baseline {}: {}", path, error))
+}
+
+/// Compiles and runs every example, printing a table of costs. Returns whether all programs
+/// ran and none became slower than the baseline allows.
+pub fn run(options: &BenchOptions) -> bool {
+    let mut paths: Vec<_> = match fs::read_dir(&options.directory) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == "imp"))
+            .collect(),
+        Err(error) => {
+            eprintln!("error: cannot read {}: {}", options.directory, error);
+            return false;
+        }
+    };
+    paths.sort();
+
+    let baseline = if options.update {
+        BTreeMap::new()
+    } else {
+        match read_baseline(&options.baseline) {
+            Ok(baseline) => baseline,
+            Err(error) => {
+                eprintln!("error: {}", error);
+                return false;
+            }
+        }
+    };
+
+    let mut success = true;
+    let mut measurements = BTreeMap::new();
+    let (mut total, mut baseline_total) = (0u64, 0u64);
+    println!("{:<12} {:>12} {:>12} {:>9} {:>13}", "program", "cycles", "baseline", "change", "instructions");
+    for path in paths {
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let measurement = match measure(&path, options.compiler) {
+            Ok(measurement) => measurement,
+            Err(error) => {
+                println!("{:<12} error: {}", name, error);
+                success = false;
+                continue;
+            }
+        };
+        total += measurement.cycles;
+        match baseline.get(&name) {
+            Some(reference) => {
+                baseline_total += reference.cycles;
+                let change = percent_change(reference.cycles, measurement.cycles);
+                let regression = change > options.tolerance;
+                success &= !regression;
+                println!(
+                    "{:<12} {:>12} {:>12} {:>+8.2}% {:>13}{}",
+                    name, measurement.cycles, reference.cycles, change, measurement.instructions,
+                    if regression { "  SLOWER" } else { "" },
+                );
+            }
+            None => println!("{:<12} {:>12} {:>12} {:>9} {:>13}", name, measurement.cycles, "-", "-", measurement.instructions),
+        }
+        measurements.insert(name, measurement);
+    }
+    if baseline_total > 0 {
+        println!("{:<12} {:>12} {:>12} {:>+8.2}%", "total", total, baseline_total, percent_change(baseline_total, total));
+    }
+
+    if options.update && !success {
+        eprintln!("error: some programs failed, the baseline is left unchanged");
+    } else if options.update {
+        if let Err(error) = write_baseline(&options.baseline, &measurements) {
+            eprintln!("error: {}", error);
+            return false;
+        }
+        println!("baseline written to {}", options.baseline);
+    } else if !success {
+        eprintln!("error: some programs failed or are more than {}% slower than the baseline", options.tolerance);
+    }
+    success
+}
+
+fn percent_change(reference: u64, value: u64) -> f64 {
+    (value as f64 - reference as f64) / reference.max(1) as f64 * 100.0
+}
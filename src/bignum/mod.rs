@@ -0,0 +1,417 @@
+use std::cmp::Ordering;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Limbs are kept small enough that a product of two fits in u64.
+const LIMB_BITS: u32 = 32;
+/// Largest power of ten in a limb, used when converting to and from decimal.
+const DECIMAL_BASE: u64 = 1_000_000_000;
+const DECIMAL_DIGITS: usize = 9;
+
+/// Arbitrary-precision natural number, used for number literals and by the virtual machine
+/// in bignum mode.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Natural {
+    /// Little-endian limbs without trailing zeros; zero has none.
+    limbs: Vec<u32>,
+}
+
+impl Natural {
+    pub fn is_zero(&self) -> bool {
+        self.limbs.is_empty()
+    }
+
+    fn normalize(mut self) -> Self {
+        while self.limbs.last() == Some(&0) {
+            self.limbs.pop();
+        }
+        self
+    }
+
+    /// Value of the number if it fits in u64.
+    pub fn to_u64(&self) -> Option<u64> {
+        match self.limbs[..] {
+            [] => Some(0),
+            [low] => Some(low as u64),
+            [low, high] => Some(low as u64 | (high as u64) << LIMB_BITS),
+            _ => None,
+        }
+    }
+
+    /// Number of significant bits.
+    pub fn bits(&self) -> u64 {
+        match self.limbs.last() {
+            None => 0,
+            Some(high) => (self.limbs.len() as u64 - 1) * LIMB_BITS as u64 + (LIMB_BITS - high.leading_zeros()) as u64,
+        }
+    }
+
+    pub fn bit(&self, index: u64) -> bool {
+        let limb = (index / LIMB_BITS as u64) as usize;
+        limb < self.limbs.len() && self.limbs[limb] >> (index % LIMB_BITS as u64) & 1 == 1
+    }
+
+    pub fn add(&self, other: &Natural) -> Natural {
+        let mut limbs = Vec::with_capacity(self.limbs.len().max(other.limbs.len()) + 1);
+        let mut carry = 0u64;
+        for i in 0..self.limbs.len().max(other.limbs.len()) {
+            let sum = *self.limbs.get(i).unwrap_or(&0) as u64 + *other.limbs.get(i).unwrap_or(&0) as u64 + carry;
+            limbs.push(sum as u32);
+            carry = sum >> LIMB_BITS;
+        }
+        limbs.push(carry as u32);
+        Natural { limbs }.normalize()
+    }
+
+    /// Difference of the numbers, or zero if `other` is larger.
+    pub fn saturating_sub(&self, other: &Natural) -> Natural {
+        if *self <= *other {
+            return Natural::default();
+        }
+        let mut limbs = Vec::with_capacity(self.limbs.len());
+        let mut borrow = 0i64;
+        for i in 0..self.limbs.len() {
+            let mut difference = self.limbs[i] as i64 - *other.limbs.get(i).unwrap_or(&0) as i64 - borrow;
+            borrow = 0;
+            if difference < 0 {
+                difference += 1 << LIMB_BITS;
+                borrow = 1;
+            }
+            limbs.push(difference as u32);
+        }
+        Natural { limbs }.normalize()
+    }
+
+    pub fn mul(&self, other: &Natural) -> Natural {
+        let mut limbs = vec![0u32; self.limbs.len() + other.limbs.len()];
+        for (i, left) in self.limbs.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, right) in other.limbs.iter().enumerate() {
+                let product = *left as u64 * *right as u64 + limbs[i + j] as u64 + carry;
+                limbs[i + j] = product as u32;
+                carry = product >> LIMB_BITS;
+            }
+            limbs[i + other.limbs.len()] = carry as u32;
+        }
+        Natural { limbs }.normalize()
+    }
+
+    /// Quotient and remainder of the division, both zero for a zero divisor like on the machine.
+    pub fn div_rem(&self, divisor: &Natural) -> (Natural, Natural) {
+        let (mut quotient, mut remainder) = (Natural::default(), Natural::default());
+        if divisor.is_zero() {
+            return (quotient, remainder);
+        }
+        let one = Natural::from(1);
+        for index in (0..self.bits()).rev() {
+            remainder = remainder.shl(1);
+            if self.bit(index) {
+                remainder = remainder.add(&one);
+            }
+            quotient = quotient.shl(1);
+            if remainder >= *divisor {
+                remainder = remainder.saturating_sub(divisor);
+                quotient = quotient.add(&one);
+            }
+        }
+        (quotient, remainder)
+    }
+
+    pub fn shl(&self, shift: u32) -> Natural {
+        let mut result = self.clone();
+        for _ in 0..shift {
+            let mut carry = 0;
+            for limb in result.limbs.iter_mut() {
+                let next = *limb >> (LIMB_BITS - 1);
+                *limb = *limb << 1 | carry;
+                carry = next;
+            }
+            result.limbs.push(carry);
+        }
+        result.normalize()
+    }
+
+    pub fn shr(&self, shift: u32) -> Natural {
+        let mut result = self.clone();
+        for _ in 0..shift {
+            let mut carry = 0;
+            for limb in result.limbs.iter_mut().rev() {
+                let next = *limb & 1;
+                *limb = *limb >> 1 | carry << (LIMB_BITS - 1);
+                carry = next;
+            }
+        }
+        result.normalize()
+    }
+
+    /// Multiplies by a small factor and adds a small term, used when reading decimal digits.
+    fn mul_add_small(&self, factor: u64, term: u64) -> Natural {
+        let mut limbs = Vec::with_capacity(self.limbs.len() + 2);
+        let mut carry = term;
+        for limb in &self.limbs {
+            let product = *limb as u64 * factor + carry;
+            limbs.push(product as u32);
+            carry = product >> LIMB_BITS;
+        }
+        while carry != 0 {
+            limbs.push(carry as u32);
+            carry >>= LIMB_BITS;
+        }
+        Natural { limbs }.normalize()
+    }
+
+    /// Divides by a small divisor, returning the quotient and the remainder.
+    fn div_rem_small(&self, divisor: u64) -> (Natural, u64) {
+        let mut limbs = vec![0; self.limbs.len()];
+        let mut remainder = 0u64;
+        for i in (0..self.limbs.len()).rev() {
+            let current = remainder << LIMB_BITS | self.limbs[i] as u64;
+            limbs[i] = (current / divisor) as u32;
+            remainder = current % divisor;
+        }
+        (Natural { limbs }.normalize(), remainder)
+    }
+}
+
+impl From<u64> for Natural {
+    fn from(value: u64) -> Self {
+        Natural { limbs: vec![value as u32, (value >> LIMB_BITS) as u32] }.normalize()
+    }
+}
+
+impl PartialOrd for Natural {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Natural {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.limbs.len().cmp(&other.limbs.len()).then_with(|| self.limbs.iter().rev().cmp(other.limbs.iter().rev()))
+    }
+}
+
+impl FromStr for Natural {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || !s.bytes().all(|byte| byte.is_ascii_digit()) {
+            return Err(format!("invalid number `{}`", s));
+        }
+        let mut result = Natural::default();
+        for chunk in s.as_bytes().chunks(DECIMAL_DIGITS) {
+            let digits: u64 = std::str::from_utf8(chunk).unwrap().parse().unwrap();
+            result = result.mul_add_small(10u64.pow(chunk.len() as u32), digits);
+        }
+        Ok(result)
+    }
+}
+
+impl Display for Natural {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut chunks = Vec::new();
+        let mut rest = self.clone();
+        while !rest.is_zero() {
+            let (quotient, remainder) = rest.div_rem_small(DECIMAL_BASE);
+            chunks.push(remainder);
+            rest = quotient;
+        }
+        match chunks.split_last() {
+            None => write!(f, "0"),
+            Some((first, others)) => {
+                write!(f, "{}", first)?;
+                for chunk in others.iter().rev() {
+                    write!(f, "{:09}", chunk)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Arbitrary-precision integer; the machine only produces naturals, but READ accepts
+/// negative numbers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Integer {
+    negative: bool,
+    magnitude: Natural,
+}
+
+impl Integer {
+    fn new(negative: bool, magnitude: Natural) -> Integer {
+        Integer { negative: negative && !magnitude.is_zero(), magnitude }
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        // Negating in i128 lets a magnitude of 2^63 give i64::MIN.
+        let magnitude = i128::from(self.magnitude.to_u64()?);
+        i64::try_from(if self.negative { -magnitude } else { magnitude }).ok()
+    }
+
+    pub fn add(&self, other: &Integer) -> Integer {
+        if self.negative == other.negative {
+            return Integer::new(self.negative, self.magnitude.add(&other.magnitude));
+        }
+        match self.magnitude.cmp(&other.magnitude) {
+            Ordering::Less => Integer::new(other.negative, other.magnitude.saturating_sub(&self.magnitude)),
+            _ => Integer::new(self.negative, self.magnitude.saturating_sub(&other.magnitude)),
+        }
+    }
+
+    pub fn sub(&self, other: &Integer) -> Integer {
+        self.add(&Integer::new(!other.negative, other.magnitude.clone()))
+    }
+
+    pub fn shl(&self) -> Integer {
+        Integer::new(self.negative, self.magnitude.shl(1))
+    }
+
+    /// Halves the number rounding down, like an arithmetic shift.
+    pub fn shr(&self) -> Integer {
+        if self.negative {
+            Integer::new(true, self.magnitude.add(&Natural::from(1)).shr(1))
+        } else {
+            Integer::new(false, self.magnitude.shr(1))
+        }
+    }
+}
+
+impl From<i64> for Integer {
+    fn from(value: i64) -> Self {
+        Integer::new(value < 0, Natural::from(value.unsigned_abs()))
+    }
+}
+
+impl PartialOrd for Integer {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Integer {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, false) => self.magnitude.cmp(&other.magnitude),
+            (true, true) => other.magnitude.cmp(&self.magnitude),
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+        }
+    }
+}
+
+impl FromStr for Integer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let magnitude = digits.parse().map_err(|_| format!("invalid number `{}`", s))?;
+        Ok(Integer::new(negative, magnitude))
+    }
+}
+
+impl Display for Integer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.negative {
+            write!(f, "-")?;
+        }
+        write!(f, "{}", self.magnitude)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn natural(text: &str) -> Natural {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn addition_carries_into_the_next_limb() {
+        let sum = Natural::from(u32::MAX as u64).add(&Natural::from(1));
+        assert_eq!(sum, Natural::from(1 << 32));
+        assert_eq!(natural("18446744073709551615").add(&Natural::from(1)).to_string(), "18446744073709551616");
+    }
+
+    #[test]
+    fn subtraction_borrows_from_the_next_limb() {
+        assert_eq!(Natural::from(1 << 32).saturating_sub(&Natural::from(1)), Natural::from(u32::MAX as u64));
+        let difference = natural("18446744073709551616").saturating_sub(&Natural::from(1));
+        assert_eq!(difference.to_string(), "18446744073709551615");
+    }
+
+    #[test]
+    fn subtraction_saturates_at_zero() {
+        assert!(Natural::from(3).saturating_sub(&Natural::from(5)).is_zero());
+        assert!(Natural::from(5).saturating_sub(&Natural::from(5)).is_zero());
+    }
+
+    #[test]
+    fn zero_has_no_limbs() {
+        assert!(Natural::from(0).is_zero());
+        assert_eq!(Natural::from(0), Natural::default());
+        assert_eq!(Natural::default().to_string(), "0");
+        assert_eq!(natural("000"), Natural::default());
+        assert_eq!(Natural::default().bits(), 0);
+    }
+
+    #[test]
+    fn decimal_form_round_trips() {
+        for text in ["0", "1", "999999999", "1000000000", "4294967296", "1000000000000000000000000000001", "1267650600228229401496703205376"] {
+            assert_eq!(natural(text).to_string(), text);
+        }
+        assert!("12a".parse::<Natural>().is_err());
+        assert!("".parse::<Natural>().is_err());
+    }
+
+    #[test]
+    fn shifts_cross_limbs() {
+        assert_eq!(Natural::from(1).shl(100).to_string(), "1267650600228229401496703205376");
+        assert_eq!(Natural::from(1).shl(100).shr(68), Natural::from(1 << 32));
+        assert_eq!(Natural::from(1).shl(100).bits(), 101);
+    }
+
+    #[test]
+    fn multiplication_carries_across_limbs() {
+        assert_eq!(Natural::from(u32::MAX as u64).mul(&Natural::from(u32::MAX as u64)), Natural::from(18446744065119617025));
+        let square = Natural::from(u64::MAX).mul(&Natural::from(u64::MAX));
+        assert_eq!(square.to_string(), "340282366920938463426481119284349108225");
+        assert!(square.mul(&Natural::default()).is_zero());
+    }
+
+    #[test]
+    fn division_by_a_multi_limb_divisor() {
+        let (quotient, remainder) = natural("1267650600228229401496703217721").div_rem(&natural("1099511627779"));
+        assert_eq!(quotient, Natural::from(1152921504603701248));
+        assert_eq!(remainder, Natural::from(9449529));
+        let (quotient, remainder) = Natural::from(5).div_rem(&natural("1099511627779"));
+        assert!(quotient.is_zero());
+        assert_eq!(remainder, Natural::from(5));
+    }
+
+    #[test]
+    fn division_by_zero_gives_zero() {
+        let (quotient, remainder) = natural("1267650600228229401496703217721").div_rem(&Natural::default());
+        assert!(quotient.is_zero() && remainder.is_zero());
+    }
+
+    #[test]
+    fn integer_signs() {
+        let minus_five: Integer = "-5".parse().unwrap();
+        assert_eq!(minus_five.add(&Integer::from(3)).to_string(), "-2");
+        assert_eq!(minus_five.sub(&Integer::from(-5)), Integer::from(0));
+        assert_eq!(minus_five.shr(), Integer::from(-3));
+        assert_eq!(Integer::from(-0), Integer::default());
+    }
+
+    #[test]
+    fn integer_to_i64_covers_the_whole_range() {
+        assert_eq!(Integer::from(i64::MIN).to_i64(), Some(i64::MIN));
+        assert_eq!(Integer::from(i64::MAX).to_i64(), Some(i64::MAX));
+        assert_eq!(Integer::from(i64::MAX).add(&Integer::from(1)).to_i64(), None);
+        assert_eq!(Integer::from(i64::MIN).sub(&Integer::from(1)).to_i64(), None);
+    }
+}
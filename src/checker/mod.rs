@@ -0,0 +1,376 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::*;
+use crate::emitter::error::{CompilerError, CompilerWarning, Diagnostics};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Scalar,
+    Array(Num),
+    ScalarParameter,
+    ArrayParameter,
+}
+
+impl SymbolKind {
+    fn is_array(&self) -> bool {
+        matches!(self, SymbolKind::Array(_) | SymbolKind::ArrayParameter)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+}
+
+/// Variables visible in the main program or in a single procedure, in declaration order.
+/// Procedure parameters come first, followed by local declarations.
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    pub symbols: Vec<Symbol>,
+}
+
+impl Scope {
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    pub fn parameters(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols
+            .iter()
+            .filter(|symbol| matches!(symbol.kind, SymbolKind::ScalarParameter | SymbolKind::ArrayParameter))
+    }
+
+    pub fn locals(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols
+            .iter()
+            .filter(|symbol| matches!(symbol.kind, SymbolKind::Scalar | SymbolKind::Array(_)))
+    }
+
+    /// Adds a symbol, reporting a duplicate if the name is already taken in this scope.
+    fn declare(&mut self, name: &SourceIdent, kind: SymbolKind, diagnostics: &mut Diagnostics) {
+        if self.get(&name.0).is_some() {
+            diagnostics.push(CompilerError::DuplicateVariableDeclaration(name.0.clone(), name.1));
+            return;
+        }
+        self.symbols.push(Symbol { name: name.0.clone(), kind });
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProcedureSymbols {
+    pub position: usize,
+    pub scope: Scope,
+}
+
+/// Result of semantic analysis: every name in the program resolved to its declaration.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    pub main: Scope,
+    pub procedures: HashMap<String, ProcedureSymbols>,
+}
+
+/// Semantic analysis of a whole program, run before any code is generated.
+/// Every procedure body is checked once on its own, whether it is called or not.
+struct Checker {
+    symbols: SymbolTable,
+    diagnostics: Diagnostics,
+    initialised_variables: HashSet<String>,
+    /// Variables of the scope being checked whose value is read, including by a callee.
+    used_variables: HashSet<String>,
+    /// First assignment of every variable of the scope being checked.
+    assignments: HashMap<String, usize>,
+    /// Calls between procedures, by caller, in source order.
+    call_graph: HashMap<String, Vec<SourceIdent>>,
+}
+
+pub fn check(program: &Program) -> (SymbolTable, Diagnostics) {
+    let mut checker = Checker {
+        symbols: SymbolTable::default(),
+        diagnostics: Diagnostics::default(),
+        initialised_variables: HashSet::new(),
+        used_variables: HashSet::new(),
+        assignments: HashMap::new(),
+        call_graph: HashMap::new(),
+    };
+    checker.check_program(program);
+    checker.check_call_graph();
+    (checker.symbols, checker.diagnostics)
+}
+
+/// Depth-first search of the call graph from `procedure`, reporting every call that leads
+/// back to a procedure on `path`. Calls on a reported cycle are added to `cyclic_calls`.
+fn find_cycles<'a>(
+    procedure: &'a str,
+    call_graph: &'a HashMap<String, Vec<SourceIdent>>,
+    path: &mut Vec<&'a str>,
+    finished: &mut HashSet<&'a str>,
+    cyclic_calls: &mut HashSet<(&'a str, usize)>,
+    diagnostics: &mut Diagnostics,
+) {
+    path.push(procedure);
+    for callee in call_graph.get(procedure).into_iter().flatten() {
+        if let Some(start) = path.iter().position(|caller| *caller == callee.0) {
+            let mut cycle: Vec<String> = path[start..].iter().map(|name| name.to_string()).collect();
+            cycle.push(callee.0.clone());
+            diagnostics.push(CompilerError::RecursiveProcedureCall(callee.0.clone(), callee.1, cycle));
+            // Every call on the cycle goes from one element of `path` to the next.
+            for (caller, next) in path[start..].iter().zip(path[start + 1..].iter()) {
+                for call in call_graph[*caller].iter().filter(|call| call.0 == *next) {
+                    cyclic_calls.insert((caller, call.1));
+                }
+            }
+            cyclic_calls.insert((procedure, callee.1));
+        } else if !finished.contains(callee.0.as_str()) {
+            find_cycles(&callee.0, call_graph, path, finished, cyclic_calls, diagnostics);
+        }
+    }
+    path.pop();
+    finished.insert(procedure);
+}
+
+fn declaration_scope(declarations: &Option<Declarations>, scope: &mut Scope, diagnostics: &mut Diagnostics) {
+    for declaration in declarations.iter().flatten() {
+        match declaration {
+            DeclarationVariant::Base(id) => scope.declare(id, SymbolKind::Scalar, diagnostics),
+            DeclarationVariant::NumIndexed(id, size) => scope.declare(id, SymbolKind::Array(*size), diagnostics),
+        }
+    }
+}
+
+impl Checker {
+    fn check_program(&mut self, program: &Program) {
+        for procedure in program.0.iter().flatten() {
+            let ((name, arguments), declarations, _) = procedure;
+            if self.symbols.procedures.contains_key(&name.0) {
+                self.diagnostics.push(CompilerError::DuplicateProcedureDeclaration(name.0.clone(), name.1));
+                continue;
+            }
+            let mut scope = Scope::default();
+            for argument in arguments {
+                match argument {
+                    ArgumentsDeclarationVariant::Base(id) => {
+                        scope.declare(id, SymbolKind::ScalarParameter, &mut self.diagnostics)
+                    }
+                    ArgumentsDeclarationVariant::Table(id) => {
+                        scope.declare(id, SymbolKind::ArrayParameter, &mut self.diagnostics)
+                    }
+                }
+            }
+            declaration_scope(declarations, &mut scope, &mut self.diagnostics);
+            self.symbols.procedures.insert(
+                name.0.clone(),
+                ProcedureSymbols { position: name.1, scope },
+            );
+        }
+        declaration_scope(&program.1 .0, &mut self.symbols.main, &mut self.diagnostics);
+
+        for ((name, _), _, commands) in program.0.iter().flatten() {
+            let Some(procedure) = self.symbols.procedures.get(&name.0) else { continue };
+            // Duplicate definitions are reported above; only the first one is checked.
+            if procedure.position != name.1 {
+                continue;
+            }
+            let scope = procedure.scope.clone();
+            self.initialised_variables = scope.parameters().map(|symbol| symbol.name.clone()).collect();
+            self.check_scope(commands, &scope, Some(&name.0));
+        }
+        let scope = self.symbols.main.clone();
+        self.initialised_variables = HashSet::new();
+        self.check_scope(&program.1 .1, &scope, None);
+    }
+
+    /// Checks the body of the main program or of a procedure, then warns about its local
+    /// variables that are assigned but never read. Parameters are not reported, since the
+    /// caller may read what is assigned to them.
+    fn check_scope(&mut self, commands: &Commands, scope: &Scope, procedure: Option<&str>) {
+        self.used_variables = HashSet::new();
+        self.assignments = HashMap::new();
+        self.check_commands(commands, scope, procedure);
+        for symbol in scope.locals() {
+            if let (Some(byte), false) = (self.assignments.get(&symbol.name), self.used_variables.contains(&symbol.name)) {
+                self.diagnostics.warn(CompilerWarning::AssignedButNeverUsed(symbol.name.clone(), *byte));
+            }
+        }
+    }
+
+    /// Reports cycles of calls with the procedures on them, and calls of procedures declared
+    /// after the caller that are not already part of a reported cycle.
+    fn check_call_graph(&mut self) {
+        let mut procedures: Vec<(&String, &ProcedureSymbols)> = self.symbols.procedures.iter().collect();
+        procedures.sort_by_key(|(_, procedure)| procedure.position);
+
+        let mut finished = HashSet::new();
+        let mut cyclic_calls = HashSet::new();
+        for (name, _) in &procedures {
+            if !finished.contains(name.as_str()) {
+                let mut path = Vec::new();
+                find_cycles(name, &self.call_graph, &mut path, &mut finished, &mut cyclic_calls, &mut self.diagnostics);
+            }
+        }
+
+        for (caller, procedure) in &procedures {
+            for callee in self.call_graph.get(*caller).into_iter().flatten() {
+                let callee_position = self.symbols.procedures[&callee.0].position;
+                if callee_position > procedure.position && !cyclic_calls.contains(&(caller.as_str(), callee.1)) {
+                    self.diagnostics.push(CompilerError::ProcedureCalledBeforeDeclaration(callee.0.clone(), callee.1));
+                }
+            }
+        }
+    }
+
+    fn check_commands(&mut self, commands: &Commands, scope: &Scope, procedure: Option<&str>) {
+        for command in commands {
+            self.check_command(command, scope, procedure);
+        }
+    }
+
+    fn check_command(&mut self, command: &Command, scope: &Scope, procedure: Option<&str>) {
+        match command {
+            Command::Assign(identifier, expression) => {
+                self.check_identifier(identifier, scope);
+                self.check_expression(expression, scope);
+                self.initialise(identifier);
+            }
+            Command::If(condition, commands, else_commands) => {
+                self.check_condition(condition, scope);
+                self.check_commands(commands, scope, procedure);
+                if let Some(else_commands) = else_commands {
+                    self.check_commands(else_commands, scope, procedure);
+                }
+            }
+            Command::While(condition, commands) => {
+                self.check_condition(condition, scope);
+                self.check_commands(commands, scope, procedure);
+            }
+            Command::Repeat(commands, condition) => {
+                self.check_commands(commands, scope, procedure);
+                self.check_condition(condition, scope);
+            }
+            Command::ProcCall((callee, arguments)) => self.check_call(callee, arguments, scope, procedure),
+            Command::Read(identifier) => {
+                self.check_identifier(identifier, scope);
+                self.initialise(identifier);
+            }
+            Command::Write(value) => self.check_value(value, scope),
+        }
+    }
+
+    fn check_call(&mut self, callee: &SourceIdent, arguments: &Arguments, scope: &Scope, procedure: Option<&str>) {
+        let Some(callee_symbols) = self.symbols.procedures.get(&callee.0) else {
+            self.diagnostics.push(CompilerError::UndeclaredProcedure(callee.0.clone(), callee.1));
+            return;
+        };
+        if let Some(procedure) = procedure {
+            self.call_graph.entry(procedure.to_string()).or_default().push(callee.clone());
+        }
+        let parameters: Vec<SymbolKind> = callee_symbols.scope.parameters().map(|symbol| symbol.kind).collect();
+        if parameters.len() != arguments.len() {
+            self.diagnostics.push(CompilerError::WrongNumberOfArguments(callee.0.clone(), callee.1));
+            return;
+        }
+        for (argument, parameter) in arguments.iter().zip(parameters) {
+            match scope.get(&argument.0) {
+                None => self.diagnostics.push(CompilerError::UndeclaredVariable(argument.0.clone(), argument.1)),
+                Some(symbol) if symbol.kind.is_array() != parameter.is_array() => {
+                    self.diagnostics.push(CompilerError::WrongArgumentType(argument.0.clone(), argument.1))
+                }
+                // Arguments are passed by reference, so the callee may initialise them.
+                Some(_) => {
+                    self.initialised_variables.insert(argument.0.clone());
+                    self.used_variables.insert(argument.0.clone());
+                }
+            }
+        }
+    }
+
+    fn check_expression(&mut self, expression: &Expression, scope: &Scope) {
+        match expression {
+            Expression::Value(value) => self.check_value(value, scope),
+            Expression::Add(value_0, value_1)
+            | Expression::Sub(value_0, value_1)
+            | Expression::Mul(value_0, value_1)
+            | Expression::Div(value_0, value_1)
+            | Expression::Mod(value_0, value_1) => {
+                self.check_value(value_0, scope);
+                self.check_value(value_1, scope);
+            }
+        }
+    }
+
+    fn check_condition(&mut self, condition: &Condition, scope: &Scope) {
+        match condition {
+            Condition::Equal(value_0, value_1)
+            | Condition::NotEqual(value_0, value_1)
+            | Condition::Greater(value_0, value_1)
+            | Condition::Lower(value_0, value_1)
+            | Condition::GreaterOrEqual(value_0, value_1)
+            | Condition::LowerOrEqual(value_0, value_1) => {
+                self.check_value(value_0, scope);
+                self.check_value(value_1, scope);
+            }
+        }
+    }
+
+    /// Checks a value that is read, warning about variables that were never assigned before.
+    fn check_value(&mut self, value: &Value, scope: &Scope) {
+        if let Value::Id(identifier) = value {
+            self.check_identifier(identifier, scope);
+            let (Identifier::Base(id) | Identifier::NumIndexed(id, _) | Identifier::PidIndexed(id, _)) = identifier;
+            self.read(id, scope);
+        }
+    }
+
+    /// Resolves an identifier and checks that it is used according to its declaration.
+    fn check_identifier(&mut self, identifier: &Identifier, scope: &Scope) {
+        match identifier {
+            Identifier::Base(id) => match scope.get(&id.0) {
+                None => self.diagnostics.push(CompilerError::UndeclaredVariable(id.0.clone(), id.1)),
+                Some(symbol) if symbol.kind.is_array() => {
+                    self.diagnostics.push(CompilerError::IncorrectUseOfVariable(id.0.clone(), id.1))
+                }
+                Some(_) => {}
+            },
+            Identifier::NumIndexed(id, index) => match scope.get(&id.0).map(|symbol| symbol.kind) {
+                None => self.diagnostics.push(CompilerError::UndeclaredVariable(id.0.clone(), id.1)),
+                Some(SymbolKind::Scalar | SymbolKind::ScalarParameter) => {
+                    self.diagnostics.push(CompilerError::IncorrectUseOfVariable(id.0.clone(), id.1))
+                }
+                Some(SymbolKind::Array(size)) if *index >= size => {
+                    self.diagnostics.push(CompilerError::IndexOutOfBounds(id.0.clone(), id.1))
+                }
+                Some(_) => {}
+            },
+            Identifier::PidIndexed(id, index_id) => {
+                match scope.get(&id.0) {
+                    None => self.diagnostics.push(CompilerError::UndeclaredVariable(id.0.clone(), id.1)),
+                    Some(symbol) if !symbol.kind.is_array() => {
+                        self.diagnostics.push(CompilerError::IncorrectUseOfVariable(id.0.clone(), id.1))
+                    }
+                    Some(_) => {}
+                }
+                match scope.get(&index_id.0) {
+                    None => self.diagnostics.push(CompilerError::UndeclaredVariable(index_id.0.clone(), index_id.1)),
+                    Some(symbol) if symbol.kind.is_array() => {
+                        self.diagnostics.push(CompilerError::ArrayUsedAsIndex(index_id.0.clone(), index_id.1))
+                    }
+                    Some(_) => self.read(index_id, scope),
+                }
+            }
+        }
+    }
+
+    /// Records a read of a variable, warning if it was never assigned before.
+    fn read(&mut self, id: &SourceIdent, scope: &Scope) {
+        self.used_variables.insert(id.0.clone());
+        if scope.get(&id.0).is_some() && !self.initialised_variables.contains(&id.0) {
+            self.diagnostics.warn(CompilerWarning::UninitialisedVariable(id.0.clone(), id.1));
+        }
+    }
+
+    fn initialise(&mut self, identifier: &Identifier) {
+        let (Identifier::Base(id) | Identifier::NumIndexed(id, _) | Identifier::PidIndexed(id, _)) = identifier;
+        self.initialised_variables.insert(id.0.clone());
+        self.assignments.entry(id.0.clone()).or_insert(id.1);
+    }
+}
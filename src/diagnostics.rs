@@ -0,0 +1,118 @@
+use std::fmt::Display;
+
+use lalrpop_util::ParseError;
+
+/// Source file being compiled, used to turn byte offsets carried by errors
+/// into `file:line:column` locations with a snippet of the offending line.
+pub struct SourceFile<'a> {
+    path: &'a str,
+    text: &'a str,
+}
+
+impl<'a> SourceFile<'a> {
+    pub fn new(path: &'a str, text: &'a str) -> Self {
+        Self { path, text }
+    }
+
+    pub fn text(&self) -> &'a str {
+        self.text
+    }
+
+    /// Returns 1-based line and column (counted in characters) of a byte offset.
+    pub fn location(&self, byte: usize) -> (usize, usize) {
+        let byte = byte.min(self.text.len());
+        let before = &self.text[..byte];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let column = self.text[line_start..byte].chars().count() + 1;
+        (line, column)
+    }
+
+    fn line_text(&self, line: usize) -> &str {
+        self.text.lines().nth(line - 1).unwrap_or("").trim_end_matches('\r')
+    }
+
+    /// Renders a message of the given severity (`error`, `warning`) pointing at
+    /// `length` characters starting at `byte`.
+    pub fn render(&self, severity: &str, byte: usize, length: usize, message: &str) -> String {
+        let (line, column) = self.location(byte);
+        let number = line.to_string();
+        let gutter = " ".repeat(number.len());
+        let source_line = self.line_text(line);
+        let padding: String = source_line
+            .chars()
+            .take(column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        format!(
+            "{}: {}\n{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}\n",
+            severity,
+            message,
+            gutter,
+            self.path,
+            line,
+            column,
+            gutter,
+            number,
+            source_line,
+            gutter,
+            padding,
+            "^".repeat(length.max(1)),
+        )
+    }
+}
+
+/// Error raised by a grammar action, with the span of the offending input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocatedError {
+    pub start: usize,
+    pub end: usize,
+    pub message: &'static str,
+}
+
+/// Turns a parser error into the byte offset and length of the offending token
+/// and a message listing what the parser expected instead.
+pub fn describe_parse_error<T: Display>(
+    error: &ParseError<usize, T, LocatedError>,
+) -> (usize, usize, String) {
+    match error {
+        ParseError::InvalidToken { location } => (*location, 1, "invalid token".to_string()),
+        ParseError::UnrecognizedEof { location, expected } => (
+            *location,
+            1,
+            format!("unexpected end of file{}", describe_expected(expected)),
+        ),
+        ParseError::UnrecognizedToken { token: (start, token, end), expected } => (
+            *start,
+            end - start,
+            format!("unexpected token `{}`{}", token, describe_expected(expected)),
+        ),
+        ParseError::ExtraToken { token: (start, token, end) } => (
+            *start,
+            end - start,
+            format!("extra token `{}` after the end of the program", token),
+        ),
+        ParseError::User { error } => (error.start, error.end - error.start, error.message.to_string()),
+    }
+}
+
+fn describe_expected(expected: &[String]) -> String {
+    if expected.is_empty() {
+        return String::new();
+    }
+    let names: Vec<String> = expected.iter().map(|name| describe_terminal(name)).collect();
+    if names.len() == 1 {
+        format!(", expected {}", names[0])
+    } else {
+        format!(", expected one of {}", names.join(", "))
+    }
+}
+
+/// Grammar terminal names are quoted literals or regexes; show them as the user would write them.
+fn describe_terminal(name: &str) -> String {
+    match name {
+        "r#\"[0-9]+\"#" => "number".to_string(),
+        "r#\"[_a-z]+\"#" => "identifier".to_string(),
+        _ => format!("`{}`", name.trim_matches('"')),
+    }
+}
@@ -0,0 +1,74 @@
+use std::fmt::Display;
+
+use lalrpop_util::ParseError;
+
+use crate::checker;
+use crate::diagnostics::{describe_parse_error, LocatedError, SourceFile};
+use crate::emitter::error::CompilerError;
+use crate::emitter::{Emitter, Options};
+use crate::lexparse;
+
+/// Outcome of compiling one source file. Diagnostics are already rendered against the source
+/// and left to the caller to print.
+#[derive(Debug, Default)]
+pub struct Compilation {
+    /// Text of the target machine code, if the program has no errors.
+    pub assembly: Option<String>,
+    pub warnings: Vec<String>,
+    pub errors: Vec<String>,
+    /// Memory cells used by the compiled program.
+    pub memory_footprint: u64,
+    /// Instructions the peephole pass removed.
+    pub peephole_savings: usize,
+}
+
+/// Compiles a program to the text of the target machine code.
+pub fn compile(source: &SourceFile, options: Options) -> Compilation {
+    let mut compilation = Compilation::default();
+    let mut syntax_errors = Vec::new();
+    let parsed = lexparse::ProgramParser::new().parse(&mut syntax_errors, source.text());
+    for recovered in &syntax_errors {
+        compilation.errors.push(render_syntax_error(&recovered.error, source));
+    }
+
+    let ast = match parsed {
+        Ok(ast) => ast,
+        Err(error) => {
+            compilation.errors.push(render_syntax_error(&error, source));
+            return compilation;
+        }
+    };
+    // Semantic checks still run on a recovered AST so that one compile reports as much as possible.
+    let (symbols, diagnostics) = checker::check(&ast);
+    // Warnings are about uses of variables, which a recovered AST loses with the commands
+    // it replaced.
+    if syntax_errors.is_empty() {
+        for warning in diagnostics.sorted_warnings() {
+            let length = warning.get_identifier().chars().count();
+            compilation.warnings.push(source.render("warning", warning.get_byte(), length, &warning.to_string()));
+        }
+    }
+    if !diagnostics.is_empty() {
+        compilation.errors.extend(diagnostics.sorted().into_iter().map(|error| render_semantic_error(error, source)));
+        return compilation;
+    }
+    if !syntax_errors.is_empty() {
+        return compilation;
+    }
+    let mut pseudo_assembler = Emitter::new(ast, symbols, options);
+    pseudo_assembler.construct();
+    compilation.memory_footprint = pseudo_assembler.memory_footprint();
+    compilation.peephole_savings = pseudo_assembler.peephole_savings();
+    compilation.assembly = Some(pseudo_assembler.emit());
+    compilation
+}
+
+fn render_syntax_error<T: Display>(error: &ParseError<usize, T, LocatedError>, source: &SourceFile) -> String {
+    let (byte, length, message) = describe_parse_error(error);
+    source.render("error", byte, length, &format!("syntax error: {}", message))
+}
+
+fn render_semantic_error(error: CompilerError, source: &SourceFile) -> String {
+    let length = error.get_identifier().chars().count();
+    source.render("error", error.get_byte(), length, &error.to_string())
+}
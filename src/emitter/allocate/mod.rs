@@ -0,0 +1,200 @@
+//! Register allocation for variables used in loops.
+//!
+//! Inside a loop entered only at its start, the variables accessed most often are kept in
+//! registers instead of memory: loaded once before the loop if their value on entry may be
+//! read, and written back at every exit only if the loop writes them and they are read
+//! afterwards. Array cells are never kept in registers, since indirect accesses may reach
+//! them, and neither are the variables of loops with calls, since the callee may access
+//! them through references and overwrites every register.
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+use crate::emitter::lower::needs_routine;
+use crate::emitter::Registers::{self, *};
+use crate::ir::liveness::LiveCells;
+use crate::ir::{Address, Instr, Label, Program, Temp};
+
+/// Registers for variables of loops without multiplication or division routines, leaving
+/// B, C and G for temporaries.
+const VARIABLE_REGISTERS: [Registers; 4] = [H, F, E, D];
+/// Registers for variables of loops with the routines, which overwrite B to F. G is kept
+/// for temporaries when a routine runs while one is still needed, and for the return
+/// address of shared routines.
+const SAFE_REGISTERS: [Registers; 2] = [H, G];
+
+/// Weight of an access for every inner loop enclosing it.
+const NESTING_WEIGHT: u64 = 8;
+/// Least weighted number of accesses worth a register.
+const MINIMUM_WEIGHT: u64 = 2;
+
+/// Variables kept in registers across one loop.
+#[derive(Debug)]
+pub struct Promotion {
+    /// Index of the label starting the loop.
+    pub start: usize,
+    /// Index of the last instruction of the loop.
+    pub end: usize,
+    pub registers: Vec<(u64, Registers)>,
+    /// Variables loaded before the loop.
+    pub loads: Vec<u64>,
+    /// Variables written back after the instruction at each index: the label of an exit, or
+    /// the last instruction of the loop when it falls through.
+    pub write_backs: Vec<(usize, Vec<u64>)>,
+}
+
+struct Planner<'a> {
+    program: &'a Program,
+    live: &'a [LiveCells],
+    /// Positions of the operations entering a shared routine with STRK G.
+    shared: &'a [usize],
+    /// First and last instruction of every loop, ordered by start.
+    loops: Vec<(usize, usize)>,
+    labels: HashMap<Label, usize>,
+    /// Indices of the jumps and branches to each label.
+    jumps: HashMap<Label, Vec<usize>>,
+}
+
+fn falls_through(instruction: &Instr) -> bool {
+    !matches!(instruction, Instr::Jump(_) | Instr::Return(_) | Instr::Halt)
+}
+
+fn defined(instruction: &Instr) -> Option<Temp> {
+    match instruction {
+        Instr::Load(temp, _) | Instr::Binary(temp, ..) | Instr::Read(temp) => Some(*temp),
+        _ => None,
+    }
+}
+
+/// Chooses the variables to keep in registers, at most one loop deep: once a loop is
+/// chosen, the loops nested in it are not considered.
+pub fn plan(program: &Program, live: &[LiveCells], shared: &[usize]) -> Vec<Promotion> {
+    let mut labels = HashMap::new();
+    let mut jumps: HashMap<Label, Vec<usize>> = HashMap::new();
+    for (index, instruction) in program.code.iter().enumerate() {
+        match instruction {
+            Instr::Label(label) => {
+                labels.insert(*label, index);
+            }
+            Instr::Jump(label) | Instr::Branch(_, _, _, label) => jumps.entry(*label).or_default().push(index),
+            _ => {}
+        }
+    }
+    let loops = program.loops();
+
+    let planner = Planner { program, live, shared, loops, labels, jumps };
+    let mut promotions: Vec<Promotion> = Vec::new();
+    for (start, end) in planner.loops.iter().copied() {
+        if promotions.last().is_some_and(|outer| start <= outer.end) {
+            continue;
+        }
+        if let Some(promotion) = planner.promote(start, end) {
+            promotions.push(promotion);
+        }
+    }
+    promotions
+}
+
+impl Planner<'_> {
+    fn promote(&self, start: usize, end: usize) -> Option<Promotion> {
+        let code = &self.program.code;
+        let inside = |index: usize| (start..=end).contains(&index);
+        let from_outside = |label: &Label| self.jumps.get(label).into_iter().flatten().any(|jump| !inside(*jump));
+        if code[start..=end].iter().any(|instruction| matches!(instruction, Instr::Call(_) | Instr::Enter(_) | Instr::Return(_) | Instr::Halt)) {
+            return None;
+        }
+        if code[start..=end].iter().any(|instruction| matches!(instruction, Instr::Label(label) if from_outside(label))) {
+            return None;
+        }
+
+        // Exits must be reached only from the loop, so that the write-back code can follow
+        // their label.
+        let mut exits = Vec::new();
+        for instruction in &code[start..=end] {
+            let (Instr::Jump(label) | Instr::Branch(_, _, _, label)) = instruction else {
+                continue;
+            };
+            let target = self.labels[label];
+            if inside(target) || exits.contains(&target) {
+                continue;
+            }
+            let entered_before = target.checked_sub(1).is_some_and(|previous| !inside(previous) && falls_through(&code[previous]));
+            if from_outside(label) || entered_before {
+                return None;
+            }
+            exits.push(target);
+        }
+        let fallthrough = (falls_through(&code[end]) && end + 1 < code.len() && !exits.contains(&(end + 1))).then_some(end);
+
+        let mut weights: HashMap<u64, u64> = HashMap::new();
+        let mut written = HashSet::new();
+        for (index, instruction) in code.iter().enumerate().take(end + 1).skip(start) {
+            let cell = match instruction {
+                Instr::Load(_, Address::Direct(cell)) => *cell,
+                Instr::Store(Address::Direct(cell), _) => {
+                    written.insert(*cell);
+                    *cell
+                }
+                _ => continue,
+            };
+            if self.program.in_array(cell) {
+                continue;
+            }
+            let depth = self.loops.iter().filter(|(inner, back)| start < *inner && (*inner..=*back).contains(&index)).count();
+            *weights.entry(cell).or_default() += NESTING_WEIGHT.saturating_pow(depth as u32);
+        }
+        let mut candidates: Vec<(u64, u64)> = weights.into_iter().filter(|(_, weight)| *weight >= MINIMUM_WEIGHT).collect();
+        candidates.sort_unstable_by_key(|(cell, weight)| (Reverse(*weight), *cell));
+
+        let routines: Vec<usize> = (start..=end)
+            .filter(|index| matches!(&code[*index], Instr::Binary(_, operation, left, right) if needs_routine(*operation, left, right)))
+            .collect();
+        let shared = routines.iter().any(|index| self.shared.contains(index));
+        let kept = routines.iter().any(|index| self.keeps_temp_across(*index));
+        // G also holds the return address of a shared routine.
+        let pool: &[Registers] = match (routines.is_empty(), shared, kept) {
+            (true, _, _) => &VARIABLE_REGISTERS,
+            (false, false, false) => &SAFE_REGISTERS,
+            (false, true, true) => &[],
+            (false, _, _) => &SAFE_REGISTERS[..1],
+        };
+        let registers: Vec<(u64, Registers)> = candidates.into_iter()
+            .zip(pool.iter().copied())
+            .map(|((cell, _), register)| (cell, register))
+            .collect();
+        if registers.is_empty() {
+            return None;
+        }
+
+        let live_at = |index: usize| -> Vec<u64> {
+            registers.iter()
+                .map(|(cell, _)| *cell)
+                .filter(|cell| written.contains(cell) && self.live[index].contains(*cell, false))
+                .collect()
+        };
+        let loads = registers.iter()
+            .map(|(cell, _)| *cell)
+            .filter(|cell| self.live[start].contains(*cell, false))
+            .collect();
+        let write_backs = exits.iter()
+            .map(|exit| (*exit, live_at(*exit)))
+            .chain(fallthrough.map(|end| (end, live_at(end + 1))))
+            .filter(|(_, cells)| !cells.is_empty())
+            .collect();
+        Some(Promotion { start, end, registers, loads, write_backs })
+    }
+
+    /// Whether a temporary computed before the instruction is read after it.
+    fn keeps_temp_across(&self, index: usize) -> bool {
+        let code = &self.program.code;
+        let mut later = index + 1;
+        while later < code.len() && !matches!(code[later], Instr::Label(_)) && !code[later - 1].ends_block() {
+            let computed_before = code[later].uses().into_iter().any(|temp| !code[index..later].iter().any(|instruction| defined(instruction) == Some(temp)));
+            if computed_before {
+                return true;
+            }
+            later += 1;
+        }
+        false
+    }
+}
@@ -1,4 +1,6 @@
-#[derive(Debug, Clone)]
+use std::fmt::Display;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum CompilerError {
     UndeclaredVariable(String, usize),
     UndeclaredProcedure(String, usize),
@@ -8,23 +10,146 @@ pub enum CompilerError {
     WrongArgumentType(String, usize),
     DuplicateVariableDeclaration(String, usize),
     DuplicateProcedureDeclaration(String, usize),
-    RecursiveProcedureCall(String, usize),
+    /// Call closing a cycle in the call graph; holds the procedures on the cycle, starting
+    /// and ending with the called one.
+    RecursiveProcedureCall(String, usize, Vec<String>),
     WrongNumberOfArguments(String, usize),
+    ProcedureCalledBeforeDeclaration(String, usize),
 }
 
 impl CompilerError {
     pub fn get_byte(&self) -> usize {
         match self {
-            CompilerError::UndeclaredVariable(_, line)
-            | CompilerError::UndeclaredProcedure(_, line)
-            | CompilerError::IncorrectUseOfVariable(_, line)
-            | CompilerError::IndexOutOfBounds(_, line)
-            | CompilerError::ArrayUsedAsIndex(_, line)
-            | CompilerError::WrongArgumentType(_, line)
-            | CompilerError::DuplicateVariableDeclaration(_, line)
-            | CompilerError::DuplicateProcedureDeclaration(_, line)
-            | CompilerError::RecursiveProcedureCall(_, line)
-            | CompilerError::WrongNumberOfArguments(_, line) => *line,
+            CompilerError::UndeclaredVariable(_, byte)
+            | CompilerError::UndeclaredProcedure(_, byte)
+            | CompilerError::IncorrectUseOfVariable(_, byte)
+            | CompilerError::IndexOutOfBounds(_, byte)
+            | CompilerError::ArrayUsedAsIndex(_, byte)
+            | CompilerError::WrongArgumentType(_, byte)
+            | CompilerError::DuplicateVariableDeclaration(_, byte)
+            | CompilerError::DuplicateProcedureDeclaration(_, byte)
+            | CompilerError::RecursiveProcedureCall(_, byte, _)
+            | CompilerError::WrongNumberOfArguments(_, byte)
+            | CompilerError::ProcedureCalledBeforeDeclaration(_, byte) => *byte,
+        }
+    }
+
+    pub fn get_identifier(&self) -> &str {
+        match self {
+            CompilerError::UndeclaredVariable(id, _)
+            | CompilerError::UndeclaredProcedure(id, _)
+            | CompilerError::IncorrectUseOfVariable(id, _)
+            | CompilerError::IndexOutOfBounds(id, _)
+            | CompilerError::ArrayUsedAsIndex(id, _)
+            | CompilerError::WrongArgumentType(id, _)
+            | CompilerError::DuplicateVariableDeclaration(id, _)
+            | CompilerError::DuplicateProcedureDeclaration(id, _)
+            | CompilerError::RecursiveProcedureCall(id, _, _)
+            | CompilerError::WrongNumberOfArguments(id, _)
+            | CompilerError::ProcedureCalledBeforeDeclaration(id, _) => id,
         }
     }
 }
+
+impl Display for CompilerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let id = self.get_identifier();
+        match self {
+            CompilerError::UndeclaredVariable(..) => write!(f, "undeclared variable `{}`", id),
+            CompilerError::UndeclaredProcedure(..) => write!(f, "undeclared procedure `{}`", id),
+            CompilerError::IncorrectUseOfVariable(..) => {
+                write!(f, "incorrect use of variable `{}` (array used as scalar or scalar used as array)", id)
+            }
+            CompilerError::IndexOutOfBounds(..) => write!(f, "index out of bounds for array `{}`", id),
+            CompilerError::ArrayUsedAsIndex(..) => write!(f, "array `{}` used as an index", id),
+            CompilerError::WrongArgumentType(..) => write!(f, "argument `{}` does not match the parameter type", id),
+            CompilerError::DuplicateVariableDeclaration(..) => {
+                write!(f, "duplicate declaration of variable `{}`", id)
+            }
+            CompilerError::DuplicateProcedureDeclaration(..) => {
+                write!(f, "duplicate declaration of procedure `{}`", id)
+            }
+            CompilerError::RecursiveProcedureCall(_, _, cycle) => {
+                write!(f, "recursive call of procedure `{}` ({})", id, cycle.join(" -> "))
+            }
+            CompilerError::WrongNumberOfArguments(..) => {
+                write!(f, "wrong number of arguments in call to `{}`", id)
+            }
+            CompilerError::ProcedureCalledBeforeDeclaration(..) => {
+                write!(f, "procedure `{}` is called before its declaration", id)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompilerWarning {
+    UninitialisedVariable(String, usize),
+    AssignedButNeverUsed(String, usize),
+}
+
+impl CompilerWarning {
+    pub fn get_byte(&self) -> usize {
+        match self {
+            CompilerWarning::UninitialisedVariable(_, byte)
+            | CompilerWarning::AssignedButNeverUsed(_, byte) => *byte,
+        }
+    }
+
+    pub fn get_identifier(&self) -> &str {
+        match self {
+            CompilerWarning::UninitialisedVariable(id, _)
+            | CompilerWarning::AssignedButNeverUsed(id, _) => id,
+        }
+    }
+}
+
+impl Display for CompilerWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompilerWarning::UninitialisedVariable(..) => {
+                write!(f, "variable `{}` used before initialisation", self.get_identifier())
+            }
+            CompilerWarning::AssignedButNeverUsed(..) => {
+                write!(f, "variable `{}` is assigned but never used", self.get_identifier())
+            }
+        }
+    }
+}
+
+/// Collects errors and warnings found while walking the whole program so they can be reported together.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    errors: Vec<CompilerError>,
+    warnings: Vec<CompilerWarning>,
+}
+
+impl Diagnostics {
+    pub fn push(&mut self, error: CompilerError) {
+        self.errors.push(error);
+    }
+
+    pub fn warn(&mut self, warning: CompilerWarning) {
+        if !self.warnings.contains(&warning) {
+            self.warnings.push(warning);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    /// Returns the recorded errors ordered by their position in the source.
+    pub fn sorted(&self) -> Vec<CompilerError> {
+        let mut errors = self.errors.clone();
+        errors.sort_by_key(|error| error.get_byte());
+        errors
+    }
+
+    /// Returns the recorded warnings ordered by their position in the source.
+    pub fn sorted_warnings(&self) -> Vec<CompilerWarning> {
+        let mut warnings = self.warnings.clone();
+        warnings.sort_by_key(|warning| warning.get_byte());
+        warnings
+    }
+}
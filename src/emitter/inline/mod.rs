@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::ast::*;
+use crate::checker::SymbolTable;
+use crate::emitter::instruct::ProcedureBuilder;
+use crate::emitter::{put_literal_in, Registers};
+use crate::vm::{MEMORY_COST, OTHER_COST};
+
+/// How procedure calls are compiled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InlineMode {
+    /// Every call site gets its own copy of the procedure body.
+    Always,
+    /// Every procedure is compiled once and entered with STRK/JUMP.
+    Never,
+    /// Decided per call site by `should_inline`.
+    #[default]
+    Auto,
+}
+
+impl FromStr for InlineMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(InlineMode::Always),
+            "never" => Ok(InlineMode::Never),
+            "auto" => Ok(InlineMode::Auto),
+            _ => Err(format!("unknown inline mode `{}` (expected always, never or auto)", s)),
+        }
+    }
+}
+
+/// Iterations assumed for every loop enclosing a call site.
+pub(crate) const ASSUMED_LOOP_ITERATIONS: u64 = 10;
+/// Cycles one extra instruction of code is worth; trades run time against code size.
+pub(crate) const CYCLES_PER_INSTRUCTION: u64 = 10;
+
+/// Instructions emitted around an out-of-line body: prologue and epilogue.
+const FRAME_SIZE: u64 = 16;
+/// Instructions emitted at a call site per argument (slot address, argument address, STORE).
+const ARGUMENT_SIZE: u64 = 12;
+
+/// Rough shape of a procedure body used to weigh inlining against calling.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BodyEstimate {
+    /// Approximate number of emitted instructions.
+    pub size: u64,
+    /// Number of places reading or writing a parameter. Out of line each of them
+    /// pays one more LOAD to fetch the address of the argument.
+    pub parameter_uses: u64,
+}
+
+/// Where and how often a procedure is called.
+#[derive(Debug, Clone, Copy)]
+pub struct CallSite {
+    /// Number of call sites of the procedure in the whole program.
+    pub call_sites: usize,
+    pub arguments: usize,
+    /// Number of loops enclosing this call site.
+    pub loop_depth: u32,
+}
+
+pub fn estimate(commands: &Commands, parameters: &[String]) -> BodyEstimate {
+    let mut estimate = BodyEstimate::default();
+    for command in commands {
+        estimate_command(command, parameters, &mut estimate);
+    }
+    estimate
+}
+
+fn estimate_command(command: &Command, parameters: &[String], estimate: &mut BodyEstimate) {
+    match command {
+        Command::Assign(identifier, expression) => {
+            estimate_identifier(identifier, parameters, estimate);
+            estimate.size += 2;
+            match expression {
+                Expression::Value(value) => estimate_value(value, parameters, estimate),
+                Expression::Add(value_0, value_1)
+                | Expression::Sub(value_0, value_1)
+                | Expression::Mul(value_0, value_1)
+                | Expression::Div(value_0, value_1)
+                | Expression::Mod(value_0, value_1) => {
+                    estimate_value(value_0, parameters, estimate);
+                    estimate_value(value_1, parameters, estimate);
+                    estimate.size += 2;
+                }
+            }
+            estimate.size += match expression {
+                Expression::Mul(..) => 25,
+                Expression::Div(..) => 24,
+                Expression::Mod(..) => 17,
+                _ => 0,
+            };
+        }
+        Command::If(condition, commands, else_commands) => {
+            estimate_condition(condition, parameters, estimate);
+            for command in commands.iter().chain(else_commands.iter().flatten()) {
+                estimate_command(command, parameters, estimate);
+            }
+        }
+        Command::While(condition, commands) | Command::Repeat(commands, condition) => {
+            estimate_condition(condition, parameters, estimate);
+            for command in commands {
+                estimate_command(command, parameters, estimate);
+            }
+        }
+        Command::ProcCall((_, arguments)) => {
+            estimate.size += ARGUMENT_SIZE * arguments.len() as u64 + 2;
+            estimate.parameter_uses += arguments.iter().filter(|argument| parameters.contains(&argument.0)).count() as u64;
+        }
+        Command::Read(identifier) => {
+            estimate_identifier(identifier, parameters, estimate);
+            estimate.size += 3;
+        }
+        Command::Write(value) => {
+            estimate_value(value, parameters, estimate);
+            estimate.size += 1;
+        }
+    }
+}
+
+fn estimate_condition(condition: &Condition, parameters: &[String], estimate: &mut BodyEstimate) {
+    match condition {
+        Condition::Equal(value_0, value_1)
+        | Condition::NotEqual(value_0, value_1)
+        | Condition::Greater(value_0, value_1)
+        | Condition::Lower(value_0, value_1)
+        | Condition::GreaterOrEqual(value_0, value_1)
+        | Condition::LowerOrEqual(value_0, value_1) => {
+            estimate_value(value_0, parameters, estimate);
+            estimate_value(value_1, parameters, estimate);
+            estimate.size += 6;
+        }
+    }
+}
+
+fn estimate_value(value: &Value, parameters: &[String], estimate: &mut BodyEstimate) {
+    match value {
+        Value::Num(num) => estimate.size += put_literal_in(Registers::A, num).len() as u64,
+        Value::Id(identifier) => {
+            estimate_identifier(identifier, parameters, estimate);
+            estimate.size += 1;
+        }
+    }
+}
+
+fn estimate_identifier(identifier: &Identifier, parameters: &[String], estimate: &mut BodyEstimate) {
+    let (id, index) = match identifier {
+        Identifier::Base(id) | Identifier::NumIndexed(id, _) => (id, None),
+        Identifier::PidIndexed(id, index_id) => (id, Some(index_id)),
+    };
+    estimate.size += 8;
+    if parameters.contains(&id.0) {
+        estimate.parameter_uses += 1;
+    }
+    if let Some(index_id) = index {
+        estimate.size += 10;
+        if parameters.contains(&index_id.0) {
+            estimate.parameter_uses += 1;
+        }
+    }
+}
+
+/// Decides whether a call site should get a copy of the procedure body.
+///
+/// Inlining saves the cycles of passing argument addresses, saving and loading the return
+/// address and loading parameter addresses on each use; calling saves the code of the body.
+/// A procedure called from a single place is always inlined, since its out-of-line copy
+/// would be as large as the inlined one.
+pub fn should_inline(mode: InlineMode, body: &BodyEstimate, site: &CallSite) -> bool {
+    match mode {
+        InlineMode::Always => true,
+        InlineMode::Never => false,
+        InlineMode::Auto => {
+            if site.call_sites <= 1 {
+                return true;
+            }
+            let arguments = site.arguments as u64;
+            let call_cycles = arguments * (MEMORY_COST + 12 * OTHER_COST)
+                + 2 * (MEMORY_COST + 8 * OTHER_COST)
+                + 3 * OTHER_COST
+                + body.parameter_uses * MEMORY_COST;
+            let executions = ASSUMED_LOOP_ITERATIONS.saturating_pow(site.loop_depth);
+            let call_size = ARGUMENT_SIZE * arguments + 2;
+            let growth = body.size.saturating_sub(call_size);
+            let shared_body = (body.size + FRAME_SIZE) / site.call_sites as u64;
+            call_cycles.saturating_mul(executions) >= growth.saturating_sub(shared_body) * CYCLES_PER_INSTRUCTION
+        }
+    }
+}
+
+/// Everything needed to decide a call site, shared by the frame layout pass and the
+/// emitter so that both see the same calls inlined.
+pub struct CallPolicy<'a> {
+    pub mode: InlineMode,
+    pub procedures: &'a HashMap<String, ProcedureBuilder>,
+    pub symbols: &'a SymbolTable,
+    pub call_sites: &'a HashMap<String, usize>,
+}
+
+impl CallPolicy<'_> {
+    pub fn inline_call(&self, procedure: &str, arguments: usize, loop_depth: u32) -> bool {
+        let (Some(builder), Some(symbols)) = (self.procedures.get(procedure), self.symbols.procedures.get(procedure)) else {
+            return false;
+        };
+        let parameters: Vec<String> = symbols.scope.parameters().map(|symbol| symbol.name.clone()).collect();
+        let body = estimate(&builder.commands, &parameters);
+        let site = CallSite {
+            call_sites: self.call_sites.get(procedure).copied().unwrap_or(0),
+            arguments,
+            loop_depth,
+        };
+        should_inline(self.mode, &body, &site)
+    }
+}
@@ -1,12 +1,14 @@
-use crate::ast::{
-ArgumentsDeclarationVariant, Command, Commands, Condition, Declarations, Expression,
-Identifier, Procedure, Value,
-};
-use crate::emitter::Registers;
+use std::str::FromStr;
+
+use crate::ast::{Commands, Procedure};
+use crate::emitter::Registers::{self, *};
+use crate::ir::Label;
+
+use Instruction::*;
 
 #[allow(dead_code)]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
-/// Jumps are relative in our pseudo-Instructions
+/// Machine instructions with symbolic jump targets, resolved by `Emitter::emit`.
 pub enum Instruction {
     Read,
     Write,
@@ -21,191 +23,126 @@ pub enum Instruction {
     Dec(Registers),
     Shl(Registers),
     Shr(Registers),
-    Strk,
+    Strk(Registers),
     Jumpr(Registers),
-    Jump(i64),
-    Jpos(i64),
-    Jzero(i64),
+    Jump(Label),
+    Jpos(Label),
+    Jzero(Label),
     Halt,
-    Mul,
-    Div,
-    Mod,
+    /// Marks the position of the next instruction; emits nothing.
+    Label(Label),
+}
+
+impl Instruction {
+    /// Register the instruction overwrites, if any.
+    pub fn written(&self) -> Option<Registers> {
+        match self {
+            Read | Load(_) | Add(_) | Sub(_) | Get(_) => Some(A),
+            Put(register) | Rst(register) | Inc(register) | Dec(register) | Shl(register) | Shr(register) | Strk(register) => Some(*register),
+            Write | Store(_) | Jumpr(_) | Jump(_) | Jpos(_) | Jzero(_) | Halt | Label(_) => None,
+        }
+    }
 }
 
+/// How multiplications, divisions and modulos without a cheaper form are compiled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoutineMode {
+    /// Every use gets its own copy of the routine.
+    Inline,
+    /// Every routine is compiled once, entered with STRK G and left with JUMPR G.
+    Shared,
+    /// Decided per routine from its uses and how often they run.
+    #[default]
+    Auto,
+}
 
+impl FromStr for RoutineMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inline" => Ok(RoutineMode::Inline),
+            "shared" => Ok(RoutineMode::Shared),
+            "auto" => Ok(RoutineMode::Auto),
+            _ => Err(format!("unknown routine mode `{}` (expected inline, shared or auto)", s)),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct ProcedureBuilder {
-    name: String,
-    pub(crate) declared_arguments: Vec<ArgumentsDeclarationVariant>,
-    pub(crate) declarations: Option<Declarations>,
     pub(crate) commands: Commands,
 }
 
 impl ProcedureBuilder {
     pub fn new(procedure: Procedure) -> Self {
-        let mut pb = Self {
-            name: procedure.0 .0 .0,
-            declared_arguments: procedure.0 .1,
-            declarations: procedure.1,
-            commands: procedure.2,
-        };
-        pb.rename_commands();
-        pb
+        Self { commands: procedure.2 }
     }
+}
 
-    fn rename_commands(&mut self) {
-        self.commands = self
-            .commands
-            .iter()
-            .cloned()
-            .map(|com| self.rename_command(com))
-            .collect();
-    }
-    fn rename_command(&self, command: Command) -> Command {
-        match command {
-            Command::Assign(id, expression) => {
-                let new_id = self.rename_indentifier(id);
-                let new_expression = match expression {
-                    Expression::Value(value) => {
-                        let new_value = self.rename_value(value);
-                        Expression::Value(new_value)
-                    }
-                    Expression::Add(value0, value1) => {
-                        let new_value0 = self.rename_value(value0);
-                        let new_value1 = self.rename_value(value1);
-                        Expression::Add(new_value0, new_value1)
-                    }
-                    Expression::Sub(value0, value1) => {
-                        let new_value0 = self.rename_value(value0);
-                        let new_value1 = self.rename_value(value1);
-                        Expression::Sub(new_value0, new_value1)
-                    }
-                    Expression::Mul(value0, value1) => {
-                        let new_value0 = self.rename_value(value0);
-                        let new_value1 = self.rename_value(value1);
-                        Expression::Mul(new_value0, new_value1)
-                    }
-                    Expression::Div(value0, value1) => {
-                        let new_value0 = self.rename_value(value0);
-                        let new_value1 = self.rename_value(value1);
-                        Expression::Div(new_value0, new_value1)
-                    }
-                    Expression::Mod(value0, value1) => {
-                        let new_value0 = self.rename_value(value0);
-                        let new_value1 = self.rename_value(value1);
-                        Expression::Mod(new_value0, new_value1)
-                    }
-                };
-                Command::Assign(new_id, new_expression)
-            }
-            Command::If(condition, commands, else_commands) => {
-                let new_condition = self.rename_condition(condition);
-                let new_commands: Vec<Command> = commands
-                    .iter()
-                    .cloned()
-                    .map(|com| self.rename_command(com))
-                    .collect();
-                let new_else_condition: Option<Vec<Command>> = else_commands.map(|else_commands| else_commands
-                            .iter()
-                            .cloned()
-                            .map(|com| self.rename_command(com))
-                            .collect());
-                Command::If(new_condition, new_commands, new_else_condition)
-            }
-            Command::While(condition, commands) => {
-                let new_condition = self.rename_condition(condition);
-                let new_commands: Vec<Command> = commands
-                    .iter()
-                    .cloned()
-                    .map(|com| self.rename_command(com))
-                    .collect();
-                Command::While(new_condition, new_commands)
-            }
-            Command::Repeat(commands, condition) => {
-                let new_condition = self.rename_condition(condition);
-                let new_commands: Vec<Command> = commands
-                    .iter()
-                    .cloned()
-                    .map(|com| self.rename_command(com))
-                    .collect();
-                Command::Repeat(new_commands, new_condition)
-            }
-            Command::ProcCall((name, arguments)) => {
-                let new_arguments: Vec<(String, usize)> = arguments.iter().map(|arg| (format!("{}@{}", arg.0, self.name), arg.1)).collect();
-                Command::ProcCall((name, new_arguments))
-            },
-            Command::Read(identifier) => {
-                let new_identifier = self.rename_indentifier(identifier);
-                Command::Read(new_identifier)
-            },
-            Command::Write(value) => {
-                let new_value = self.rename_value(value);
-                Command::Write(new_value)
-            },
-        }
-    }
-    fn rename_condition(&self, condition: Condition) -> Condition {
-        match condition {
-            Condition::Equal(value0, value1) => {
-                let new_value0 = self.rename_value(value0);
-                let new_value1 = self.rename_value(value1);
-                Condition::Equal(new_value0, new_value1)
-            }
-            Condition::NotEqual(value0, value1) => {
-                let new_value0 = self.rename_value(value0);
-                let new_value1 = self.rename_value(value1);
-                Condition::NotEqual(new_value0, new_value1)
-            }
-            Condition::Greater(value0, value1) => {
-                let new_value0 = self.rename_value(value0);
-                let new_value1 = self.rename_value(value1);
-                Condition::Greater(new_value0, new_value1)
-            }
-            Condition::Lower(value0, value1) => {
-                let new_value0 = self.rename_value(value0);
-                let new_value1 = self.rename_value(value1);
-                Condition::Lower(new_value0, new_value1)
-            }
-            Condition::GreaterOrEqual(value0, value1) => {
-                let new_value0 = self.rename_value(value0);
-                let new_value1 = self.rename_value(value1);
-                Condition::GreaterOrEqual(new_value0, new_value1)
-            }
-            Condition::LowerOrEqual(value0, value1) => {
-                let new_value0 = self.rename_value(value0);
-                let new_value1 = self.rename_value(value1);
-                Condition::LowerOrEqual(new_value0, new_value1)
-            }
-        }
-    }
-    fn rename_value(&self, value: Value) -> Value {
-        match value {
-            Value::Num(_) => value.clone(),
-            Value::Id(id) => Value::Id(self.rename_indentifier(id)),
-        }
-    }
-    fn rename_indentifier(&self, identifier: Identifier) -> Identifier {
-        match identifier {
-            Identifier::Base(id) => Identifier::Base((format!("{}@{}", id.0, self.name), id.1)),
-            Identifier::NumIndexed(id, num) => {
-                Identifier::NumIndexed((format!("{}@{}", id.0, self.name), id.1), num)
-            }
-            Identifier::PidIndexed(id, index_id) => Identifier::PidIndexed(
-                (format!("{}@{}", id.0, self.name), id.1),
-                (format!("{}@{}", index_id.0, self.name), id.1),
-            ),
-        }
+/// Multiplies two registers among B to F into A by shifting and adding, running the loop
+/// once per bit of `driver`. With `swap` set, the operands first trade places if the driver
+/// is the greater one, for operands whose values are not known when compiling. Overwrites
+/// both registers and two more of B to F.
+pub(crate) fn multiplication(labels: [Label; 4], multiplicand: Registers, driver: Registers, swap: bool) -> Vec<Instruction> {
+    let [ordered, start, even, end] = labels;
+    let mut scratch = [F, E, D, C, B].into_iter().filter(|register| *register != multiplicand && *register != driver);
+    let (product, parity) = (scratch.next().unwrap(), scratch.next().unwrap());
+    let mut instructions = Vec::new();
+    if swap {
+        instructions.extend([
+            Get(driver),
+            Sub(multiplicand),
+            Jzero(ordered),
+            Get(driver),
+            Put(product),
+            Get(multiplicand),
+            Put(driver),
+            Get(product),
+            Put(multiplicand),
+            Label(ordered),
+        ]);
     }
+    instructions.extend([
+        Rst(product),
+        Label(start),
+        Get(driver),
+        Jzero(end),
+        // The driver is odd unless clearing its lowest bit leaves it unchanged.
+        Put(parity),
+        Shr(parity),
+        Shl(parity),
+        Get(driver),
+        Sub(parity),
+        Jzero(even),
+        Get(product),
+        Add(multiplicand),
+        Put(product),
+        Label(even),
+        Shl(multiplicand),
+        Shr(driver),
+        Jump(start),
+        Label(end),
+        Get(product),
+    ]);
+    instructions
 }
 
-impl Instruction {
-    pub(crate) fn len(&self) -> u64 {
-        match self {
-            Instruction::Mul => 20,
-            Instruction::Div => 25,
-            Instruction::Mod => 26,
-            _ => 1,
-        }
+/// Divides B by C, repeatedly subtracting C * 2^k for growing k until B is lower than C;
+/// a zero divisor gives zero. Puts the quotient in A if `quotient` is set, otherwise the
+/// remainder. Overwrites B to F.
+pub(crate) fn division(labels: [Label; 4], quotient: bool) -> Vec<Instruction> {
+    let [zero, outer, inner, end] = labels;
+    let mut instructions = if quotient { vec![Rst(D)] } else { vec![] };
+    instructions.extend([Get(C), Jzero(zero), Label(outer), Get(C), Sub(B), Jpos(end), Get(C), Put(E)]);
+    if quotient {
+        instructions.extend([Rst(F), Inc(F)]);
+    }
+    instructions.extend([Label(inner), Get(E), Sub(B), Jpos(outer), Get(B), Sub(E), Put(B)]);
+    if quotient {
+        instructions.extend([Get(D), Add(F), Put(D), Shl(F)]);
     }
-}
\ No newline at end of file
+    instructions.extend([Shl(E), Jump(inner), Label(zero), Rst(B), Label(end)]);
+    instructions.push(if quotient { Get(D) } else { Get(B) });
+    instructions
+}
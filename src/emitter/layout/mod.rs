@@ -0,0 +1,134 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::*;
+use crate::checker::{Scope, SymbolKind};
+use crate::emitter::inline::CallPolicy;
+
+/// Placement of procedure activations in memory.
+///
+/// Procedures are not recursive, so memory is handed out like a stack whose shape is known
+/// at compile time. The locals of an inlined call are placed right above the memory in use
+/// at the call site and released after it, so calls made one after another share cells.
+/// A procedure compiled out of line gets one frame, placed above the highest cell in use at
+/// any of its call sites; frames of procedures that never run at the same time overlap.
+#[derive(Debug, Clone, Default)]
+pub struct Layout {
+    /// First cell of the frame of every procedure compiled out of line.
+    pub frames: HashMap<String, u64>,
+    /// Number of memory cells used by the whole program.
+    pub footprint: u64,
+}
+
+/// Cells taken by the declared variables of a scope.
+pub fn locals_size(scope: &Scope) -> u64 {
+    scope.locals()
+        .map(|symbol| match symbol.kind {
+            SymbolKind::Array(length) => length,
+            _ => 1,
+        })
+        .sum()
+}
+
+/// Cells taken by the frame of a procedure compiled out of line: the return address,
+/// one address per parameter and the locals.
+pub fn frame_size(scope: &Scope) -> u64 {
+    1 + scope.parameters().count() as u64 + locals_size(scope)
+}
+
+/// Out-of-line calls made from one activation and the highest cell it uses, both relative
+/// to the start of the activation.
+#[derive(Debug, Default)]
+struct Activation {
+    calls: Vec<(String, u64)>,
+    peak: u64,
+}
+
+/// Computes the layout for the main program, whose variables take the first `main_size` cells.
+pub fn plan(main: &Commands, main_size: u64, policy: &CallPolicy) -> Layout {
+    let mut activations: HashMap<Option<String>, Activation> = HashMap::new();
+    let mut pending = vec![None];
+    while let Some(procedure) = pending.pop() {
+        if activations.contains_key(&procedure) {
+            continue;
+        }
+        let mut activation = Activation::default();
+        match &procedure {
+            None => walk(main, main_size, 0, policy, &mut activation),
+            Some(name) => {
+                let size = frame_size(&policy.symbols.procedures[name].scope);
+                walk(&policy.procedures[name].commands, size, 0, policy, &mut activation);
+            }
+        }
+        pending.extend(activation.calls.iter().map(|(callee, _)| Some(callee.clone())));
+        activations.insert(procedure, activation);
+    }
+
+    let mut callers: HashMap<&str, Vec<(Option<&str>, u64)>> = HashMap::new();
+    for (caller, activation) in &activations {
+        for (callee, top) in &activation.calls {
+            callers.entry(callee.as_str()).or_default().push((caller.as_deref(), *top));
+        }
+    }
+    let mut layout = Layout { frames: HashMap::new(), footprint: activations[&None].peak };
+    for procedure in activations.keys().flatten() {
+        let base = frame_base(procedure, &callers, &mut layout.frames, &mut HashSet::new());
+        layout.footprint = layout.footprint.max(base + activations[&Some(procedure.clone())].peak);
+    }
+    layout
+}
+
+/// A frame starts above the memory in use at every call site of its procedure, which for
+/// calls from another procedure depends on where that procedure's frame is.
+fn frame_base<'a>(
+    procedure: &'a str,
+    callers: &HashMap<&'a str, Vec<(Option<&'a str>, u64)>>,
+    frames: &mut HashMap<String, u64>,
+    visiting: &mut HashSet<&'a str>,
+) -> u64 {
+    if let Some(base) = frames.get(procedure) {
+        return *base;
+    }
+    // A cycle can only come from a recursive program, which is rejected before code generation.
+    if !visiting.insert(procedure) {
+        return 0;
+    }
+    let mut base = 0;
+    for (caller, top) in callers.get(procedure).into_iter().flatten() {
+        let caller_base = match caller {
+            None => 0,
+            Some(caller) => frame_base(caller, callers, frames, visiting),
+        };
+        base = base.max(caller_base + top);
+    }
+    frames.insert(procedure.to_string(), base);
+    base
+}
+
+/// Follows the commands of an activation whose memory in use ends at `top`, the same way
+/// the emitter compiles them.
+fn walk(commands: &Commands, top: u64, loop_depth: u32, policy: &CallPolicy, activation: &mut Activation) {
+    activation.peak = activation.peak.max(top);
+    for command in commands {
+        match command {
+            Command::If(_, commands, else_commands) => {
+                walk(commands, top, loop_depth, policy, activation);
+                if let Some(else_commands) = else_commands {
+                    walk(else_commands, top, loop_depth, policy, activation);
+                }
+            }
+            Command::While(_, commands) | Command::Repeat(commands, _) => {
+                walk(commands, top, loop_depth + 1, policy, activation)
+            }
+            Command::ProcCall((procedure_id, arguments)) => {
+                let Some(symbols) = policy.symbols.procedures.get(&procedure_id.0) else { continue };
+                if policy.inline_call(&procedure_id.0, arguments.len(), loop_depth) {
+                    let commands = &policy.procedures[&procedure_id.0].commands;
+                    walk(commands, top + locals_size(&symbols.scope), loop_depth, policy, activation);
+                } else {
+                    activation.calls.push((procedure_id.0.clone(), top));
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,963 @@
+//! Instruction selection: turns the three-address code of `crate::ir` into machine
+//! instructions.
+//!
+//! Every instruction leaves its result in A. A temporary that is still needed when A gets
+//! overwritten moves to one of the other registers, preferably the one its next use wants:
+//! B and C for the operands of a multiplication or division, G for an address. Variables
+//! kept in registers across a loop, as planned by `crate::emitter::allocate`, take theirs
+//! away from temporaries. Multiplications and divisions used often enough outside hot
+//! loops share one copy of their routine, entered with STRK G and left with JUMPR G. A
+//! division and a modulo of the same operands run the long division once.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::emitter::allocate::{self, Promotion};
+use crate::emitter::inline::CYCLES_PER_INSTRUCTION;
+use crate::emitter::instruct::{division, multiplication, Instruction, RoutineMode};
+use crate::emitter::Registers::{self, *};
+use crate::ast::Literal;
+use crate::emitter::{put_in, put_literal_in};
+use crate::ir::{liveness, Address, BinaryOp, Instr, Label, Operand, Program, Relation, Temp};
+use crate::vm::{ARITHMETIC_COST, OTHER_COST};
+
+/// Registers that hold temporaries, in order of preference.
+const TEMP_REGISTERS: [Registers; 7] = [G, H, D, E, F, C, B];
+/// Registers overwritten by the multiplication and division routines.
+const ARITHMETIC_REGISTERS: [Registers; 5] = [B, C, D, E, F];
+
+/// Cycles a shared routine adds to every use: STRK, JUMP, two INCs and JUMPR.
+const SHARED_CALL_COST: u64 = 5;
+/// Instructions at every use of a shared routine: STRK and JUMP.
+const SHARED_CALL_SIZE: u64 = 2;
+/// Instructions a shared routine adds to its body: two INCs and JUMPR.
+const SHARED_FRAME_SIZE: u64 = 3;
+
+/// Digits of a multiplier, most significant first, each 0, 1 or -1: multiplying by it is
+/// a SHL per digit after the first, adding or subtracting the multiplicand for non-zero ones.
+fn shift_add_digits(multiplier: &Literal) -> Vec<i8> {
+    let binary: Vec<i8> = (0..multiplier.bits()).rev().map(|bit| multiplier.bit(bit) as i8).collect();
+    let Some(mut rest) = multiplier.to_u64().map(u128::from) else {
+        return binary;
+    };
+    // Non-adjacent form: runs of ones become one addition and one subtraction. Every
+    // prefix is positive, so the saturating SUB never clips.
+    let mut non_adjacent = Vec::new();
+    while rest > 0 {
+        let digit = match rest % 4 {
+            1 => 1,
+            3 => -1,
+            _ => 0,
+        };
+        rest = rest.wrapping_sub(digit as i128 as u128) >> 1;
+        non_adjacent.push(digit);
+    }
+    non_adjacent.reverse();
+    if shift_add_cost(&non_adjacent).0 < shift_add_cost(&binary).0 {
+        non_adjacent
+    } else {
+        binary
+    }
+}
+
+/// Cycles and instructions of multiplying by the digits: a SHL per digit after the first,
+/// an ADD or SUB per further non-zero one and a PUT of the multiplicand if there are any.
+fn shift_add_cost(digits: &[i8]) -> (u64, u64) {
+    let terms = digits.iter().filter(|digit| **digit != 0).count() as u64;
+    let copy = u64::from(terms > 1);
+    let cycles = (digits.len() as u64 - 1) * OTHER_COST + (terms - 1) * ARITHMETIC_COST + copy * OTHER_COST;
+    (cycles, digits.len() as u64 - 1 + terms - 1 + copy)
+}
+
+/// Cycles and instructions of multiplying by a constant with the multiplication routine:
+/// building the constant, then a loop iteration per bit of it, with three instructions
+/// more for the ones. The routine is either copied, without the operand swap, or shared,
+/// where the swap costs a comparison at least.
+fn routine_multiplication_cost(constant: &Literal) -> (u64, u64) {
+    let ones = (0..constant.bits()).filter(|bit| constant.bit(*bit)).count() as u64;
+    let build = 1 + constant.bits() + ones;
+    let loop_cycles = 4 * OTHER_COST + constant.bits() * (10 * OTHER_COST + ARITHMETIC_COST) + ones * (2 * OTHER_COST + ARITHMETIC_COST);
+    let copy = multiplication([0; 4], B, C, false).iter().filter(|instruction| !matches!(instruction, Instruction::Label(_))).count() as u64;
+    let inline = (0, copy);
+    let shared = (SHARED_CALL_COST + 2 * OTHER_COST + ARITHMETIC_COST, SHARED_CALL_SIZE);
+    let (call_cycles, call_size) = [inline, shared].into_iter()
+        .min_by_key(|(cycles, size)| cycles + size * CYCLES_PER_INSTRUCTION)
+        .unwrap();
+    (build * OTHER_COST + loop_cycles + call_cycles, build + call_size)
+}
+
+/// Whether multiplying by a constant with shifts and additions costs no more than running
+/// the multiplication routine, counting code size as well.
+fn shift_add_pays(constant: &Literal) -> bool {
+    let weigh = |(cycles, size): (u64, u64)| cycles + size * CYCLES_PER_INSTRUCTION;
+    weigh(shift_add_cost(&shift_add_digits(constant))) <= weigh(routine_multiplication_cost(constant))
+}
+
+/// Operand and constant of a multiplication, division or modulo with a constant one.
+fn constant_operand<'a>(operation: BinaryOp, left: &'a Operand, right: &'a Operand) -> Option<(&'a Operand, &'a Literal)> {
+    match (operation, left, right) {
+        (_, Operand::Const(constant), _) if constant.is_zero() => Some((right, constant)),
+        (BinaryOp::Mul, Operand::Const(constant), _) => Some((right, constant)),
+        (_, _, Operand::Const(constant)) => Some((left, constant)),
+        _ => None,
+    }
+}
+
+/// Exponent of a constant that is a power of two.
+fn power_of_two(constant: &Literal) -> Option<u64> {
+    (constant.bits() > 0 && *constant == Literal::from(1).shl(constant.bits() as u32 - 1)).then(|| constant.bits() - 1)
+}
+
+/// Whether a multiplication, division or modulo runs the general routine instead of
+/// being reduced to shifts.
+pub(super) fn needs_routine(operation: BinaryOp, left: &Operand, right: &Operand) -> bool {
+    match (operation, constant_operand(operation, left, right)) {
+        (BinaryOp::Add | BinaryOp::Sub, _) => false,
+        (_, None) => true,
+        (BinaryOp::Mul, Some((_, constant))) => !constant.is_zero() && !shift_add_pays(constant),
+        (_, Some((_, constant))) => !constant.is_zero() && *constant != Literal::from(1) && power_of_two(constant).is_none(),
+    }
+}
+
+fn routine(operation: BinaryOp, labels: [Label; 4]) -> Vec<Instruction> {
+    match operation {
+        BinaryOp::Mul => multiplication(labels, B, C, true),
+        _ => division(labels, operation == BinaryOp::Div),
+    }
+}
+
+/// Instructions of a copy of the routine of an operation.
+fn routine_size(operation: BinaryOp) -> u64 {
+    routine(operation, [0; 4]).iter().filter(|instruction| !matches!(instruction, Instruction::Label(_))).count() as u64
+}
+
+/// Uses of multiplication and division routines that enter a shared copy instead of
+/// getting their own. In auto mode only uses outside loops share it, and only when the
+/// code saved outweighs the cycles of entering and leaving the routine; copies inside
+/// loops keep running at full speed and leave G free for variables.
+fn shared_uses(program: &Program, mode: RoutineMode, paired: &HashMap<usize, usize>) -> Vec<usize> {
+    let loops = program.loops();
+    let mut shared = Vec::new();
+    for operation in [BinaryOp::Mul, BinaryOp::Div, BinaryOp::Mod] {
+        let uses = program.code.iter()
+            .enumerate()
+            .filter(|(_, instruction)| matches!(instruction, Instr::Binary(_, used, left, right) if *used == operation && needs_routine(*used, left, right)))
+            .map(|(index, _)| index)
+            .filter(|index| !paired.contains_key(index) && !paired.values().any(|first| first == index));
+        let uses: Vec<usize> = match mode {
+            RoutineMode::Inline => continue,
+            RoutineMode::Shared => uses.collect(),
+            RoutineMode::Auto => uses.filter(|index| !loops.iter().any(|(start, end)| (start..=end).contains(&index))).collect(),
+        };
+        let size = routine_size(operation);
+        let count = uses.len() as u64;
+        let saved = (count * (size - SHARED_CALL_SIZE)).saturating_sub(size + SHARED_FRAME_SIZE);
+        if mode == RoutineMode::Shared || saved * CYCLES_PER_INSTRUCTION > count * SHARED_CALL_COST {
+            shared.extend(uses);
+        }
+    }
+    shared
+}
+
+/// Where the value of an operand comes from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Source {
+    Cell(u64),
+    Const(Literal),
+}
+
+/// Finds divisions and modulos taking the same operands as the one of the other kind just
+/// before them, which leaves both results of its long division: the quotient in D and the
+/// remainder in B. Between the two only loads and stores may run, none writing an operand.
+/// Returns the index of the earlier one for every later one.
+fn pair_divisions(program: &Program) -> HashMap<usize, usize> {
+    let mut sources: HashMap<Temp, u64> = HashMap::new();
+    let mut pairs = HashMap::new();
+    let mut pending: Option<(usize, BinaryOp, [Source; 2])> = None;
+    for (index, instruction) in program.code.iter().enumerate() {
+        let reads = |cell: u64, operands: &[Source; 2]| operands.contains(&Source::Cell(cell));
+        match instruction {
+            Instr::Load(temp, address) => {
+                if let Address::Direct(cell) = address {
+                    sources.insert(*temp, *cell);
+                }
+            }
+            Instr::Store(Address::Direct(cell), _) => {
+                pending = pending.filter(|(_, _, operands)| !reads(*cell, operands));
+            }
+            Instr::Store(Address::Indirect(_), _) => {
+                pending = pending.filter(|(_, _, operands)| !operands.iter().any(|operand| matches!(operand, Source::Cell(cell) if program.in_array(*cell))));
+            }
+            Instr::Binary(_, operation @ (BinaryOp::Div | BinaryOp::Mod), left, right) if needs_routine(*operation, left, right) => {
+                let source = |operand: &Operand| match operand {
+                    Operand::Const(literal) => Some(Source::Const(literal.clone())),
+                    Operand::Temp(temp) => sources.get(temp).map(|cell| Source::Cell(*cell)),
+                };
+                let operands = source(left).zip(source(right)).map(|(left, right)| [left, right]);
+                match (pending.take(), operands) {
+                    (Some((first, earlier, earlier_operands)), Some(operands)) if earlier != *operation && earlier_operands == operands => {
+                        pairs.insert(index, first);
+                    }
+                    (_, operands) => pending = operands.map(|operands| (index, *operation, operands)),
+                }
+            }
+            _ => pending = None,
+        }
+    }
+    pairs
+}
+
+struct Lowering {
+    /// Index of the last instruction reading each temporary.
+    last_use: HashMap<Temp, usize>,
+    /// Register wanted by the first instruction reading each temporary.
+    hints: HashMap<Temp, Registers>,
+    instructions: Vec<Instruction>,
+    /// Index of the instruction being lowered.
+    position: usize,
+    /// Temporary whose value is in A.
+    accumulator: Option<Temp>,
+    registers: HashMap<Temp, Registers>,
+    /// Register of every variable kept in one in the loop being lowered.
+    variables: HashMap<u64, Registers>,
+    /// Variables to write back to memory after the instruction at each index.
+    write_backs: HashMap<usize, Vec<(u64, Registers)>>,
+    /// Registers known to hold an address, until overwritten or a label is reached.
+    addresses: Vec<(Registers, u64)>,
+    /// Positions of the instructions entering a shared routine.
+    shared_uses: Vec<usize>,
+    /// Entry of the shared routine of each operation that has one.
+    shared: Vec<(BinaryOp, Label)>,
+    /// Index of the division or modulo whose results each later one of a pair takes.
+    paired: HashMap<usize, usize>,
+    /// Temporaries only loaded for the later division or modulo of a pair, left unloaded.
+    unloaded: HashSet<Temp>,
+    /// Registers holding the results of the earlier division or modulo of a pair.
+    reserved: Vec<Registers>,
+    next_label: Label,
+}
+
+/// Lowers a whole program to machine instructions.
+pub fn lower(program: &Program, routines: RoutineMode) -> Vec<Instruction> {
+    let paired = pair_divisions(program);
+    let shared_uses = shared_uses(program, routines, &paired);
+    // Addresses wait for their store in G, unless shared routines need it.
+    let address_register = if shared_uses.is_empty() { G } else { H };
+    let mut last_use = HashMap::new();
+    let mut hints = HashMap::new();
+    for (index, instruction) in program.code.iter().enumerate() {
+        for temp in instruction.uses() {
+            last_use.insert(temp, index);
+        }
+        let wanted: Vec<(Temp, Registers)> = match instruction {
+            Instr::Binary(_, BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod, left, right) => [(left, B), (right, C)]
+                .into_iter()
+                .filter_map(|(operand, register)| match operand {
+                    Operand::Temp(temp) => Some((*temp, register)),
+                    Operand::Const(_) => None,
+                })
+                .collect(),
+            Instr::Store(Address::Indirect(temp), _) => vec![(*temp, address_register)],
+            _ => vec![],
+        };
+        for (temp, register) in wanted {
+            hints.entry(temp).or_insert(register);
+        }
+    }
+
+    let mut uses: HashMap<Temp, usize> = HashMap::new();
+    for temp in program.code.iter().flat_map(Instr::uses) {
+        *uses.entry(temp).or_default() += 1;
+    }
+    let unloaded = paired.keys()
+        .flat_map(|later| program.code[*later].uses())
+        .filter(|temp| uses[temp] == 1)
+        .collect();
+
+    let mut lowering = Lowering {
+        last_use,
+        hints,
+        instructions: Vec::new(),
+        position: 0,
+        accumulator: None,
+        registers: HashMap::new(),
+        variables: HashMap::new(),
+        write_backs: HashMap::new(),
+        addresses: Vec::new(),
+        shared_uses: shared_uses.clone(),
+        shared: Vec::new(),
+        paired,
+        unloaded,
+        reserved: Vec::new(),
+        next_label: program.labels,
+    };
+    for position in &shared_uses {
+        let Instr::Binary(_, operation, ..) = program.code[*position] else {
+            unreachable!("shared routine entered by an instruction other than an operation");
+        };
+        if !lowering.shared.iter().any(|(shared, _)| *shared == operation) {
+            let entry = lowering.new_label();
+            lowering.shared.push((operation, entry));
+        }
+    }
+    let live = liveness::live_cells(program);
+    let promotions: HashMap<usize, Promotion> = allocate::plan(program, &live, &shared_uses)
+        .into_iter()
+        .map(|promotion| (promotion.start, promotion))
+        .collect();
+    let mut loop_end = None;
+    for (position, instruction) in program.code.iter().enumerate() {
+        lowering.position = position;
+        lowering.release_dead();
+        if let Some(promotion) = promotions.get(&position) {
+            lowering.enter_loop(promotion);
+            loop_end = Some(promotion.end);
+        }
+        lowering.lower_instruction(instruction);
+        if loop_end == Some(position) {
+            lowering.variables.clear();
+        }
+        if let Some(variables) = lowering.write_backs.remove(&position) {
+            lowering.write_back(&variables);
+        }
+    }
+    for (operation, entry) in lowering.shared.clone() {
+        lowering.push(Instruction::Label(entry));
+        // G holds the address of STRK; the caller continues after the following JUMP.
+        lowering.push(Instruction::Inc(G));
+        lowering.push(Instruction::Inc(G));
+        let routine = routine(operation, lowering.labels());
+        lowering.extend(routine);
+        lowering.push(Instruction::Jumpr(G));
+    }
+    lowering.instructions
+}
+
+impl Lowering {
+    fn push(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Label(_) => self.addresses.clear(),
+            _ => {
+                let written = instruction.written();
+                self.addresses.retain(|(register, _)| Some(*register) != written);
+            }
+        }
+        self.instructions.push(instruction);
+    }
+
+    fn extend(&mut self, instructions: Vec<Instruction>) {
+        for instruction in instructions {
+            self.push(instruction);
+        }
+    }
+
+    fn new_label(&mut self) -> Label {
+        self.next_label += 1;
+        self.next_label - 1
+    }
+
+    /// Forgets temporaries no longer read, freeing their registers.
+    fn release_dead(&mut self) {
+        let (last_use, position) = (&self.last_use, self.position);
+        self.registers.retain(|temp, _| last_use.get(temp).is_some_and(|last| *last >= position));
+    }
+
+    /// Forgets where values are, at a point reached by a jump.
+    fn forget(&mut self) {
+        self.accumulator = None;
+        self.registers.clear();
+        self.addresses.clear();
+    }
+
+    /// Whether the temporary is read by the instruction at `position` or a later one.
+    fn read_from(&self, temp: Temp, position: usize) -> bool {
+        self.last_use.get(&temp).is_some_and(|last| *last >= position)
+    }
+
+    /// Loads the variables of a loop into their registers before its first instruction.
+    fn enter_loop(&mut self, promotion: &Promotion) {
+        self.variables = promotion.registers.iter().copied().collect();
+        for cell in &promotion.loads {
+            let address_register = self.address_register(*cell, true, &[]);
+            self.put_address(address_register, *cell);
+            self.push(Instruction::Load(address_register));
+            self.push(Instruction::Put(self.variables[cell]));
+        }
+        if !promotion.loads.is_empty() {
+            self.accumulator = None;
+        }
+        for (position, cells) in &promotion.write_backs {
+            let variables = cells.iter().map(|cell| (*cell, self.variables[cell])).collect();
+            self.write_backs.insert(*position, variables);
+        }
+    }
+
+    /// Stores variables kept in registers back to memory, at a point where no temporary is live.
+    fn write_back(&mut self, variables: &[(u64, Registers)]) {
+        let kept: Vec<Registers> = variables.iter().map(|(_, register)| *register).collect();
+        self.accumulator = None;
+        self.registers.clear();
+        for (cell, register) in variables {
+            self.push(Instruction::Get(*register));
+            let address_register = self.address_register(*cell, false, &kept);
+            self.put_address(address_register, *cell);
+            self.push(Instruction::Store(address_register));
+        }
+    }
+
+    /// Instructions needed to put an address in a register, and the register and address to
+    /// count from with INC or DEC when that is shorter than building it from zero.
+    fn address_source(&self, register: Registers, address: u64) -> (u64, Option<(Registers, u64)>) {
+        let fresh = put_in(register, address).len() as u64;
+        self.addresses.iter()
+            .filter(|(known, _)| *known == register || register == A)
+            .map(|(known, value)| (value.abs_diff(address) + u64::from(*known != register), Some((*known, *value))))
+            .fold((fresh, None), |best, source| if source.0 < best.0 { source } else { best })
+    }
+
+    fn put_address(&mut self, register: Registers, address: u64) {
+        match self.address_source(register, address).1 {
+            None => self.extend(put_in(register, address)),
+            Some((known, value)) => {
+                if known != register {
+                    self.push(Instruction::Get(known));
+                }
+                let step = if value < address { Instruction::Inc } else { Instruction::Dec };
+                for _ in 0..value.abs_diff(address) {
+                    self.push(step(register));
+                }
+            }
+        }
+        self.addresses.push((register, address));
+    }
+
+    /// Register to put an address in for one access: the free one where that is shortest,
+    /// sparing those holding other addresses. A is a candidate if `accumulator` is set.
+    fn address_register(&self, address: u64, accumulator: bool, avoid: &[Registers]) -> Registers {
+        TEMP_REGISTERS.into_iter()
+            .filter(|register| self.is_free(register, avoid))
+            .chain(accumulator.then_some(A))
+            .min_by_key(|register| {
+                let holds_other = self.addresses.iter().any(|(known, value)| known == register && *value != address);
+                (self.address_source(*register, address).0, holds_other, *register == A)
+            })
+            .expect("more live temporaries than registers")
+    }
+
+    fn is_free(&self, register: &Registers, avoid: &[Registers]) -> bool {
+        !avoid.contains(register)
+            && !self.registers.values().any(|used| used == register)
+            && !self.variables.values().any(|used| used == register)
+            && !self.reserved.contains(register)
+    }
+
+    fn free_register(&self, hint: Option<Registers>, avoid: &[Registers]) -> Registers {
+        let free = |register: &Registers| self.is_free(register, avoid);
+        hint.filter(free)
+            .or_else(|| TEMP_REGISTERS.into_iter().find(free))
+            .expect("more live temporaries than registers")
+    }
+
+    /// Copies the temporary in A to a register if it is read at or after `position`.
+    fn save_accumulator(&mut self, position: usize) {
+        if let Some(temp) = self.accumulator {
+            if self.read_from(temp, position) && !self.registers.contains_key(&temp) {
+                let register = self.free_register(self.hints.get(&temp).copied(), &[]);
+                self.push(Instruction::Put(register));
+                self.registers.insert(temp, register);
+            }
+        }
+    }
+
+    /// Prepares A to be overwritten before the current instruction read its operands.
+    fn clear_accumulator(&mut self) {
+        self.save_accumulator(self.position);
+        self.accumulator = None;
+    }
+
+    /// Prepares A to be overwritten by the result of the current instruction.
+    fn replace_accumulator(&mut self, temp: Option<Temp>) {
+        self.save_accumulator(self.position + 1);
+        self.accumulator = temp;
+    }
+
+    fn load_accumulator(&mut self, operand: &Operand) {
+        match operand {
+            Operand::Const(literal) => {
+                self.clear_accumulator();
+                self.extend(put_literal_in(A, literal));
+            }
+            Operand::Temp(temp) if self.accumulator == Some(*temp) => {}
+            Operand::Temp(temp) => {
+                let register = self.registers[temp];
+                self.clear_accumulator();
+                self.push(Instruction::Get(register));
+                self.accumulator = Some(*temp);
+            }
+        }
+    }
+
+    /// Puts an operand in a register other than A, leaving A unchanged. A constant goes to
+    /// a free register not in `avoid`; it is not recorded as taken, so callers pass it in
+    /// `avoid` while they need it.
+    fn load_register(&mut self, operand: &Operand, avoid: &[Registers]) -> Registers {
+        match operand {
+            Operand::Const(literal) => {
+                let register = self.free_register(None, avoid);
+                self.extend(put_literal_in(register, literal));
+                register
+            }
+            Operand::Temp(temp) => match self.registers.get(temp) {
+                Some(register) => *register,
+                None => {
+                    assert_eq!(self.accumulator, Some(*temp), "temporary read before it is written");
+                    let register = self.free_register(self.hints.get(temp).copied(), avoid);
+                    self.push(Instruction::Put(register));
+                    self.registers.insert(*temp, register);
+                    register
+                }
+            },
+        }
+    }
+
+    /// Puts the left operand in A and the right one in the returned register.
+    fn operands(&mut self, left: &Operand, right: &Operand) -> Registers {
+        match right {
+            Operand::Temp(_) => {
+                let register = self.load_register(right, &[]);
+                self.load_accumulator(left);
+                register
+            }
+            Operand::Const(_) => {
+                self.load_accumulator(left);
+                // Saved before the constant takes a free register.
+                self.save_accumulator(self.position + 1);
+                self.load_register(right, &[])
+            }
+        }
+    }
+
+    /// Leaves `left - right` in A.
+    fn subtract(&mut self, left: &Operand, right: &Operand) {
+        let register = self.operands(left, right);
+        self.replace_accumulator(None);
+        self.push(Instruction::Sub(register));
+    }
+
+    fn lower_instruction(&mut self, instruction: &Instr) {
+        match instruction {
+            Instr::Load(temp, _) if self.unloaded.contains(temp) => {}
+            Instr::Binary(temp, operation, ..) if self.paired.contains_key(&self.position) => {
+                self.clear_accumulator();
+                self.push(Instruction::Get(if *operation == BinaryOp::Div { D } else { B }));
+                self.accumulator = Some(*temp);
+                self.reserved.clear();
+            }
+            Instr::Load(temp, Address::Direct(address)) if self.variables.contains_key(address) => {
+                self.registers.insert(*temp, self.variables[address]);
+            }
+            Instr::Store(Address::Direct(address), operand) if self.variables.contains_key(address) => {
+                self.store_variable(self.variables[address], operand);
+            }
+            Instr::Load(temp, Address::Direct(address)) => {
+                self.clear_accumulator();
+                let register = self.address_register(*address, true, &[]);
+                self.put_address(register, *address);
+                self.push(Instruction::Load(register));
+                self.accumulator = Some(*temp);
+            }
+            Instr::Load(temp, Address::Indirect(address)) => {
+                let register = match self.registers.get(address) {
+                    Some(register) => *register,
+                    None => A,
+                };
+                if register != A {
+                    self.clear_accumulator();
+                }
+                self.replace_accumulator(Some(*temp));
+                self.push(Instruction::Load(register));
+            }
+            Instr::Store(Address::Direct(address), operand) => {
+                self.load_accumulator(operand);
+                let register = self.address_register(*address, false, &[]);
+                self.put_address(register, *address);
+                self.push(Instruction::Store(register));
+            }
+            Instr::Store(Address::Indirect(address), operand) => {
+                let register = self.load_register(&Operand::Temp(*address), &[]);
+                self.load_accumulator(operand);
+                self.push(Instruction::Store(register));
+            }
+            Instr::Binary(temp, BinaryOp::Add, left, right) => {
+                let (left, right) = match (left, right) {
+                    (Operand::Const(_), Operand::Temp(_)) => (right, left),
+                    (_, Operand::Temp(right_temp)) if self.accumulator == Some(*right_temp) => (right, left),
+                    _ => (left, right),
+                };
+                let register = self.operands(left, right);
+                self.replace_accumulator(Some(*temp));
+                self.push(Instruction::Add(register));
+            }
+            Instr::Binary(temp, BinaryOp::Sub, left, right) => {
+                self.subtract(left, right);
+                self.accumulator = Some(*temp);
+            }
+            Instr::Binary(temp, operation, left, right) => {
+                if self.strength_reduce(*temp, *operation, left, right) {
+                    return;
+                }
+                let shared = self.shared.iter()
+                    .find(|(shared, _)| shared == operation && self.shared_uses.contains(&self.position))
+                    .map(|(_, entry)| *entry);
+                let mut clobbered = ARITHMETIC_REGISTERS.to_vec();
+                clobbered.extend(shared.map(|_| G));
+                // The loop of a multiplication runs once per bit of its right operand, so a
+                // constant one goes there.
+                let (left, right) = match (operation, left) {
+                    (BinaryOp::Mul, Operand::Const(_)) => (right, left),
+                    _ => (left, right),
+                };
+                match (shared, operation) {
+                    (Some(entry), _) => {
+                        self.arithmetic(left, right, &clobbered);
+                        self.push(Instruction::Strk(G));
+                        self.push(Instruction::Jump(entry));
+                        self.addresses.clear();
+                    }
+                    (None, BinaryOp::Mul) => {
+                        let swap = matches!(right, Operand::Temp(_));
+                        let (left, right) = self.multiplication_operands(left, right);
+                        let labels = self.labels();
+                        self.extend(multiplication(labels, left, right, swap));
+                    }
+                    (None, _) if self.paired.values().any(|first| *first == self.position) => {
+                        self.arithmetic(left, right, &clobbered);
+                        let labels = self.labels();
+                        self.extend(division(labels, true));
+                        if *operation == BinaryOp::Mod {
+                            self.push(Instruction::Get(B));
+                        }
+                        self.reserved = vec![B, D];
+                    }
+                    (None, _) => {
+                        self.arithmetic(left, right, &clobbered);
+                        let routine = routine(*operation, self.labels());
+                        self.extend(routine);
+                    }
+                }
+                self.registers.retain(|_, register| !clobbered.contains(register));
+                self.accumulator = Some(*temp);
+            }
+            Instr::Read(temp) => {
+                self.clear_accumulator();
+                self.push(Instruction::Read);
+                self.accumulator = Some(*temp);
+            }
+            Instr::Write(operand) => {
+                self.load_accumulator(operand);
+                self.push(Instruction::Write);
+            }
+            Instr::Label(label) => {
+                self.push(Instruction::Label(*label));
+                self.forget();
+            }
+            Instr::Jump(label) => {
+                self.push(Instruction::Jump(*label));
+                self.forget();
+            }
+            Instr::Branch(relation, left, right, label) => self.branch(*relation, left, right, *label),
+            Instr::Call(label) => {
+                // The callee finds the address of STRK in H.
+                self.push(Instruction::Strk(H));
+                self.push(Instruction::Jump(*label));
+                self.forget();
+            }
+            Instr::Enter(return_slot) => {
+                self.put_address(B, *return_slot);
+                self.push(Instruction::Get(H));
+                // H holds the address of STRK; the caller continues after the following JUMP.
+                self.push(Instruction::Inc(A));
+                self.push(Instruction::Inc(A));
+                self.push(Instruction::Store(B));
+            }
+            Instr::Return(return_slot) => {
+                self.put_address(A, *return_slot);
+                self.push(Instruction::Load(A));
+                self.push(Instruction::Jumpr(A));
+                self.forget();
+            }
+            Instr::Halt => {
+                self.push(Instruction::Halt);
+                self.forget();
+            }
+        }
+    }
+
+    /// Writes a variable kept in a register, first moving elsewhere any temporary loaded
+    /// from it that is still needed.
+    fn store_variable(&mut self, register: Registers, operand: &Operand) {
+        let position = self.position;
+        let loaded: Vec<Temp> = self.registers.iter()
+            .filter(|(temp, used)| **used == register && self.read_from(**temp, position + 1) && *operand != Operand::Temp(**temp))
+            .map(|(temp, _)| *temp)
+            .collect();
+        for temp in loaded {
+            self.clear_accumulator();
+            self.registers.remove(&temp);
+            let other = self.free_register(None, &[]);
+            self.push(Instruction::Get(register));
+            self.push(Instruction::Put(other));
+            self.registers.insert(temp, other);
+            self.accumulator = Some(temp);
+        }
+        match operand {
+            Operand::Const(literal) => self.extend(put_literal_in(register, literal)),
+            Operand::Temp(temp) if self.registers.get(temp) == Some(&register) => {}
+            Operand::Temp(_) => {
+                self.load_accumulator(operand);
+                self.push(Instruction::Put(register));
+            }
+        }
+    }
+
+    /// Computes a multiplication, division or modulo with a constant operand without the
+    /// general routine where shifts do better: multiplying by shifts and additions, dividing
+    /// by a power of two by shifts and taking a remainder of one by masking. Returns whether
+    /// it did.
+    fn strength_reduce(&mut self, temp: Temp, operation: BinaryOp, left: &Operand, right: &Operand) -> bool {
+        let Some((operand, constant)) = constant_operand(operation, left, right) else {
+            return false;
+        };
+        if needs_routine(operation, left, right) {
+            return false;
+        }
+        let power = power_of_two(constant);
+        let zero = constant.is_zero() || (operation == BinaryOp::Mod && *constant == Literal::from(1));
+        if zero {
+            self.clear_accumulator();
+            self.push(Instruction::Rst(A));
+            self.accumulator = Some(temp);
+            return true;
+        }
+        match (operation, power) {
+            (BinaryOp::Mul, _) => {
+                let digits = shift_add_digits(constant);
+                self.load_accumulator(operand);
+                let register = match digits.iter().skip(1).any(|digit| *digit != 0) {
+                    true => Some(self.load_register(operand, &[])),
+                    false => None,
+                };
+                self.replace_accumulator(Some(temp));
+                for digit in digits.into_iter().skip(1) {
+                    self.push(Instruction::Shl(A));
+                    match (digit, register) {
+                        (1, Some(register)) => self.push(Instruction::Add(register)),
+                        (-1, Some(register)) => self.push(Instruction::Sub(register)),
+                        _ => {}
+                    }
+                }
+            }
+            (BinaryOp::Div, Some(power)) => {
+                self.load_accumulator(operand);
+                self.replace_accumulator(Some(temp));
+                for _ in 0..power {
+                    self.push(Instruction::Shr(A));
+                }
+            }
+            // x % 2^k = x - (x >> k << k)
+            (BinaryOp::Mod, Some(power)) => {
+                self.load_accumulator(operand);
+                let register = self.load_register(operand, &[]);
+                for _ in 0..power {
+                    self.push(Instruction::Shr(A));
+                }
+                for _ in 0..power {
+                    self.push(Instruction::Shl(A));
+                }
+                let rounded = self.free_register(None, &[register]);
+                self.push(Instruction::Put(rounded));
+                self.push(Instruction::Get(register));
+                self.push(Instruction::Sub(rounded));
+                self.accumulator = Some(temp);
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Labels for a routine.
+    fn labels<const N: usize>(&mut self) -> [Label; N] {
+        std::array::from_fn(|_| self.new_label())
+    }
+
+    /// Moves every temporary still needed after the current instruction out of the
+    /// registers it overwrites, and out of A.
+    fn protect(&mut self, clobbered: &[Registers]) {
+        let position = self.position;
+        let threatened: Vec<(Temp, Registers)> = self.registers.iter()
+            .filter(|(temp, register)| clobbered.contains(register) && self.read_from(**temp, position + 1))
+            .map(|(temp, register)| (*temp, *register))
+            .collect();
+        if !threatened.is_empty() {
+            self.clear_accumulator();
+        }
+        for (temp, register) in threatened {
+            let safe = self.free_register(None, clobbered);
+            self.push(Instruction::Get(register));
+            self.push(Instruction::Put(safe));
+            self.registers.insert(temp, safe);
+            self.accumulator = Some(temp);
+        }
+        if let Some(temp) = self.accumulator {
+            if self.read_from(temp, position + 1) && !self.registers.contains_key(&temp) {
+                let safe = self.free_register(None, clobbered);
+                self.push(Instruction::Put(safe));
+                self.registers.insert(temp, safe);
+            }
+        }
+    }
+
+    /// Puts the operands of a multiplication in two registers among B to F, leaving those
+    /// already in one where they are.
+    fn multiplication_operands(&mut self, left: &Operand, right: &Operand) -> (Registers, Registers) {
+        self.protect(&ARITHMETIC_REGISTERS);
+        let operands = [left, right];
+        let mut placed: [Option<Registers>; 2] = [None, None];
+        for (slot, operand) in operands.iter().enumerate() {
+            if let Operand::Temp(temp) = operand {
+                let register = self.registers.get(temp).copied()
+                    .filter(|register| ARITHMETIC_REGISTERS.contains(register) && !placed.contains(&Some(*register)));
+                placed[slot] = register;
+            }
+        }
+        let target = |placed: &[Option<Registers>; 2], slot: usize| {
+            [[B, C][slot]].into_iter()
+                .chain(ARITHMETIC_REGISTERS)
+                .find(|register| !placed.contains(&Some(*register)))
+                .expect("a free register for a multiplication operand")
+        };
+        // An operand in A goes first, before other operands are copied through A.
+        let order = match operands[1] {
+            Operand::Temp(temp) if self.accumulator == Some(*temp) => [1, 0],
+            _ => [0, 1],
+        };
+        for slot in order {
+            if placed[slot].is_some() {
+                continue;
+            }
+            let register = target(&placed, slot);
+            match operands[slot] {
+                Operand::Const(literal) => self.extend(put_literal_in(register, literal)),
+                Operand::Temp(temp) if self.accumulator == Some(*temp) => self.push(Instruction::Put(register)),
+                Operand::Temp(temp) => {
+                    self.push(Instruction::Get(self.registers[temp]));
+                    self.push(Instruction::Put(register));
+                    self.accumulator = Some(*temp);
+                }
+            }
+            placed[slot] = Some(register);
+        }
+        self.accumulator = None;
+        (placed[0].unwrap(), placed[1].unwrap())
+    }
+
+    /// Puts the operands of a multiplication or division in B and C, first moving every
+    /// other temporary still needed out of the registers the operation overwrites.
+    fn arithmetic(&mut self, left: &Operand, right: &Operand, clobbered: &[Registers]) {
+        self.protect(clobbered);
+
+        // An operand only in A goes straight to its place unless the other one is there.
+        let operand_temp = |operand: &Operand| match operand {
+            Operand::Temp(temp) => Some(*temp),
+            Operand::Const(_) => None,
+        };
+        let (left_temp, right_temp) = (operand_temp(left), operand_temp(right));
+        for (temp, target, other) in [(left_temp, B, right_temp), (right_temp, C, left_temp)] {
+            let other_in_target = other.and_then(|other| self.registers.get(&other)) == Some(&target);
+            if let Some(temp) = temp.filter(|temp| self.accumulator == Some(*temp)) {
+                if !self.registers.contains_key(&temp) && !other_in_target {
+                    self.push(Instruction::Put(target));
+                    self.registers.insert(temp, target);
+                }
+            }
+        }
+        for temp in [left_temp, right_temp].into_iter().flatten() {
+            if !self.registers.contains_key(&temp) {
+                self.load_register(&Operand::Temp(temp), &[B, C]);
+            }
+        }
+
+        let location = |temp: Option<Temp>| temp.map(|temp| self.registers[&temp]);
+        let (left_register, right_register) = (location(left_temp), location(right_temp));
+        if left_register == Some(C) && right_register == Some(B) {
+            self.push(Instruction::Get(C));
+            self.push(Instruction::Put(D));
+            self.push(Instruction::Get(B));
+            self.push(Instruction::Put(C));
+            self.push(Instruction::Get(D));
+            self.push(Instruction::Put(B));
+        } else if left_register == Some(C) {
+            self.place(left, left_register, B);
+            self.place(right, right_register, C);
+        } else {
+            self.place(right, right_register, C);
+            self.place(left, left_register, B);
+        }
+        self.accumulator = None;
+    }
+
+    fn place(&mut self, operand: &Operand, register: Option<Registers>, target: Registers) {
+        match (operand, register) {
+            (Operand::Const(literal), _) => self.extend(put_literal_in(target, literal)),
+            (_, Some(register)) if register == target => {}
+            (_, Some(register)) => {
+                self.push(Instruction::Get(register));
+                self.push(Instruction::Put(target));
+            }
+            (_, None) => unreachable!("operand placed in a register first"),
+        }
+    }
+
+    /// Jumps to `label` if the relation holds, computing a saturating difference of the
+    /// operands: positive means greater, zero means lower or equal.
+    fn branch(&mut self, relation: Relation, left: &Operand, right: &Operand, label: Label) {
+        match relation {
+            Relation::Greater | Relation::LowerOrEqual => self.subtract(left, right),
+            Relation::Lower | Relation::GreaterOrEqual => self.subtract(right, left),
+            Relation::Equal | Relation::NotEqual => {
+                let left_register = self.load_register(left, &[]);
+                let right_register = self.load_register(right, &[left_register]);
+                let right_in_accumulator = matches!(right, Operand::Temp(temp) if self.accumulator == Some(*temp));
+                let (first, second) = if right_in_accumulator {
+                    (right_register, left_register)
+                } else {
+                    (left_register, right_register)
+                };
+                if !right_in_accumulator && !matches!(left, Operand::Temp(temp) if self.accumulator == Some(*temp)) {
+                    self.replace_accumulator(None);
+                    self.push(Instruction::Get(first));
+                }
+                self.replace_accumulator(None);
+                self.push(Instruction::Sub(second));
+                let unequal = match relation {
+                    Relation::Equal => self.new_label(),
+                    _ => label,
+                };
+                self.push(Instruction::Jpos(unequal));
+                self.push(Instruction::Get(second));
+                self.push(Instruction::Sub(first));
+                if relation == Relation::Equal {
+                    self.push(Instruction::Jzero(label));
+                    self.push(Instruction::Label(unequal));
+                } else {
+                    self.push(Instruction::Jpos(label));
+                }
+                return;
+            }
+        }
+        let jump = match relation {
+            Relation::Greater | Relation::Lower => Instruction::Jpos,
+            _ => Instruction::Jzero,
+        };
+        self.push(jump(label));
+    }
+}
@@ -1,12 +1,22 @@
 use std::{
-    collections::{HashMap, HashSet, VecDeque}, fmt::Display
+    collections::HashMap, fmt::Display
 };
-use error::CompilerError;
-use instruct::{Instruction, ProcedureBuilder};
+use instruct::{Instruction, ProcedureBuilder, RoutineMode};
+use inline::{CallPolicy, InlineMode};
+use layout::Layout;
+use scope::{Bindings, Environment};
 
 pub mod error;
+pub mod inline;
 pub mod instruct;
+pub mod layout;
+mod allocate;
+mod lower;
+mod peephole;
+mod scope;
 use crate::ast::*;
+use crate::checker::{SymbolKind, SymbolTable};
+use crate::ir::{self, Address, BinaryOp, Instr, Operand, Relation};
 
 use Registers::*;
 
@@ -33,796 +43,653 @@ impl Display for Registers {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum VariableVariant {
     Atomic(u64),
     Table(u64, u64),
+    /// Procedure parameter: the memory cell holds the address of the caller's variable.
+    AtomicReference(u64),
+    /// Procedure array parameter: the memory cell holds the address of the caller's array.
+    TableReference(u64),
+}
+
+/// Static activation record of a procedure compiled out of line. Procedures cannot be
+/// recursive, so every procedure owns a fixed block of memory, placed by `layout::plan`.
+#[derive(Debug, Clone)]
+struct ProcedureFrame {
+    entry: ir::Label,
+    return_slot: u64,
+    parameter_slots: Vec<u64>,
+    /// First cell above the frame, where locals of calls inlined into the body go.
+    end: u64,
+    bindings: Bindings,
+}
+
+/// Code generation settings chosen on the command line.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Options {
+    pub inline: InlineMode,
+    pub routines: RoutineMode,
 }
 
 #[derive(Debug)]
 pub struct Emitter {
+    program: ir::Program,
     pseudo_assembly: Vec<Instruction>,
     procedures: HashMap<String, ProcedureBuilder>,
-    memory: HashMap<String, VariableVariant>,
-    initialisated_variables: HashSet<String>,
+    frames: HashMap<String, ProcedureFrame>,
+    /// Procedures compiled out of line, in the order their code follows main.
+    out_of_line: Vec<String>,
+    call_sites: HashMap<String, usize>,
+    layout: Layout,
+    loop_depth: u32,
+    options: Options,
+    environment: Environment,
+    symbols: SymbolTable,
+    /// First free cell of the activation being compiled, including calls inlined into it.
     memory_pointer: u64,
     ast: Program,
+    /// Instructions removed by the peephole pass.
+    peephole_savings: usize,
 }
-fn put_in_a(mut num: u64) -> Vec<Instruction> {
-    let mut instructions: Vec<Instruction> = Vec::new();
-    instructions.push(Instruction::Rst(A));
-    if num != 0 {
-        instructions.push(Instruction::Inc(A));
-        let mut sub_instructions: Vec<Instruction> = Vec::new();
-        while num != 1 {
-            if num % 2 == 1 {
-                sub_instructions.push(Instruction::Inc(A));
-                num -= 1;
-            } else {
-                sub_instructions.push(Instruction::Shl(A));
-                num /= 2;
+fn put_in(register: Registers, num: u64) -> Vec<Instruction> {
+    put_bits_in(register, 64 - num.leading_zeros() as u64, |bit| num >> bit & 1 == 1)
+}
+
+/// Puts a number literal of any size in a register.
+fn put_literal_in(register: Registers, literal: &Literal) -> Vec<Instruction> {
+    put_bits_in(register, literal.bits(), |bit| literal.bit(bit))
+}
+
+/// Builds a number in a register from its most significant bit down: one INC for the
+/// leading one, then a SHL for every further bit and an INC for every further one.
+fn put_bits_in(register: Registers, bits: u64, bit: impl Fn(u64) -> bool) -> Vec<Instruction> {
+    let mut instructions: Vec<Instruction> = vec![Instruction::Rst(register)];
+    if bits != 0 {
+        instructions.push(Instruction::Inc(register));
+        for index in (0..bits - 1).rev() {
+            instructions.push(Instruction::Shl(register));
+            if bit(index) {
+                instructions.push(Instruction::Inc(register));
             }
         }
-        sub_instructions.reverse();
-        instructions.extend(sub_instructions);
     }
     instructions
 }
 
+/// Counts the call sites of every procedure in `commands`.
+fn count_call_sites(commands: &Commands, call_sites: &mut HashMap<String, usize>) {
+    for command in commands {
+        match command {
+            Command::If(_, commands, else_commands) => {
+                count_call_sites(commands, call_sites);
+                if let Some(else_commands) = else_commands {
+                    count_call_sites(else_commands, call_sites);
+                }
+            }
+            Command::While(_, commands) | Command::Repeat(commands, _) => count_call_sites(commands, call_sites),
+            Command::ProcCall((procedure_id, _)) => *call_sites.entry(procedure_id.0.clone()).or_insert(0) += 1,
+            _ => {}
+        }
+    }
+}
+
+/// Counts the uses of every variable in `commands`, each weighing `weight` times the
+/// assumed iterations of the loops enclosing it.
+fn count_accesses(commands: &Commands, weight: u64, accesses: &mut HashMap<String, u64>) {
+    for command in commands {
+        let mut identifiers: Vec<&Identifier> = Vec::new();
+        let mut values: Vec<&Value> = Vec::new();
+        let mut arguments: &[SourceIdent] = &[];
+        let mut weight = weight;
+        match command {
+            Command::Assign(target, expression) => {
+                identifiers.push(target);
+                match expression {
+                    Expression::Value(value) => values.push(value),
+                    Expression::Add(value_0, value_1)
+                    | Expression::Sub(value_0, value_1)
+                    | Expression::Mul(value_0, value_1)
+                    | Expression::Div(value_0, value_1)
+                    | Expression::Mod(value_0, value_1) => values.extend([value_0, value_1]),
+                }
+            }
+            Command::If(condition, commands, else_commands) => {
+                values.extend(condition_values(condition));
+                count_accesses(commands, weight, accesses);
+                if let Some(else_commands) = else_commands {
+                    count_accesses(else_commands, weight, accesses);
+                }
+            }
+            Command::While(condition, commands) | Command::Repeat(commands, condition) => {
+                weight = weight.saturating_mul(inline::ASSUMED_LOOP_ITERATIONS);
+                values.extend(condition_values(condition));
+                count_accesses(commands, weight, accesses);
+            }
+            Command::ProcCall((_, call_arguments)) => arguments = call_arguments,
+            Command::Read(target) => identifiers.push(target),
+            Command::Write(value) => values.push(value),
+        }
+        identifiers.extend(values.into_iter().filter_map(|value| match value {
+            Value::Id(identifier) => Some(identifier),
+            Value::Num(_) => None,
+        }));
+        let mut names: Vec<&SourceIdent> = arguments.iter().collect();
+        for identifier in identifiers {
+            match identifier {
+                Identifier::Base(id) | Identifier::NumIndexed(id, _) => names.push(id),
+                Identifier::PidIndexed(id, index_id) => names.extend([id, index_id]),
+            }
+        }
+        for name in names {
+            *accesses.entry(name.0.clone()).or_insert(0) += weight;
+        }
+    }
+}
+
+fn condition_values(condition: &Condition) -> [&Value; 2] {
+    match condition {
+        Condition::Equal(value_0, value_1)
+        | Condition::NotEqual(value_0, value_1)
+        | Condition::Greater(value_0, value_1)
+        | Condition::Lower(value_0, value_1)
+        | Condition::GreaterOrEqual(value_0, value_1)
+        | Condition::LowerOrEqual(value_0, value_1) => [value_0, value_1],
+    }
+}
+
 impl Emitter {
-    pub fn new(ast: Program) -> Result<Emitter, CompilerError> {
+    /// Creates an emitter for a program that has already passed semantic analysis,
+    /// laying out main's variables in memory according to the symbol table.
+    pub fn new(ast: Program, symbols: SymbolTable, options: Options) -> Emitter {
         let mut procedures: HashMap<String, ProcedureBuilder> = HashMap::new();
         if let Some(procedures_ast) = ast.0.clone() {
             for procedure in procedures_ast {
-                if procedures.insert(procedure.0.0.0.clone(), ProcedureBuilder::new(procedure.clone())).is_some() {
-                    Err(CompilerError::DuplicateProcedureDeclaration(procedure.0.0.0.clone(), procedure.0.0.1.clone()))?;
-                }
+                procedures.entry(procedure.0.0.0.clone()).or_insert_with(|| ProcedureBuilder::new(procedure));
             }
         }
-        let mut memory_pointer: u64 = 0;
-        let mut memory: HashMap<String, VariableVariant> = HashMap::new();
-        if let Some(vars) = ast.1 .0.clone() {
-            for var in vars {
-                match var {
-                    DeclarationVariant::Base(id) => {
-                        memory.insert(id.0, VariableVariant::Atomic(memory_pointer));
-                        memory_pointer += 1;
-                    }
-                    DeclarationVariant::NumIndexed(id, size) => {
-                        memory.insert(id.0, VariableVariant::Table(memory_pointer, size));
-                        memory_pointer += size;
-                    }
-                }
+        // Scalars go first, the most used ones at the addresses cheapest to build, then
+        // arrays from the smallest.
+        let mut accesses = HashMap::new();
+        count_accesses(&ast.1 .1, 1, &mut accesses);
+        let mut scalars = Vec::new();
+        let mut arrays = Vec::new();
+        for symbol in symbols.main.locals() {
+            match symbol.kind {
+                SymbolKind::Array(size) => arrays.push((symbol.name.clone(), size)),
+                _ => scalars.push(symbol.name.clone()),
             }
         }
-        Ok(Emitter {
+        scalars.sort_by_key(|name| std::cmp::Reverse(accesses.get(name).copied().unwrap_or(0)));
+        arrays.sort_by_key(|(_, size)| *size);
+
+        let mut memory_pointer: u64 = 0;
+        let mut memory = Bindings::new();
+        let mut program = ir::Program::default();
+        for name in scalars {
+            memory.insert(name, VariableVariant::Atomic(memory_pointer));
+            memory_pointer += 1;
+        }
+        for (name, size) in arrays {
+            memory.insert(name, VariableVariant::Table(memory_pointer, size));
+            program.arrays.push(memory_pointer..memory_pointer + size);
+            memory_pointer += size;
+        }
+        Emitter {
+            program,
             pseudo_assembly: vec![],
             procedures,
-            memory,
+            frames: HashMap::new(),
+            out_of_line: Vec::new(),
+            call_sites: HashMap::new(),
+            layout: Layout::default(),
+            loop_depth: 0,
+            options,
+            environment: Environment::new(memory),
             memory_pointer,
             ast,
-            initialisated_variables: HashSet::new(),
-        })
+            symbols,
+            peephole_savings: 0,
+        }
     }
+    /// Assembles the program text, replacing labels with the absolute addresses of the
+    /// instructions they mark.
     pub fn emit(&self) -> String {
-        let mut assembly: Vec<String> = Vec::new();
+        let mut addresses = HashMap::new();
+        let mut address = 0;
         for instruction in &self.pseudo_assembly {
             match instruction {
-                Instruction::Read => assembly.push("READ\n".to_string()),
-                Instruction::Write => assembly.push("WRITE\n".to_string()),
-                Instruction::Load(register) => assembly.push(format!("LOAD {}\n", register)),
-                Instruction::Store(register) => assembly.push(format!("STORE {}\n", register)),
-                Instruction::Add(register) => assembly.push(format!("ADD {}\n", register)),
-                Instruction::Sub(register) => assembly.push(format!("SUB {}\n", register)),
-                Instruction::Get(register) => assembly.push(format!("GET {}\n", register)),
-                Instruction::Put(register) => assembly.push(format!("PUT {}\n", register)),
-                Instruction::Rst(register) => assembly.push(format!("RST {}\n", register)),
-                Instruction::Inc(register) => assembly.push(format!("INC {}\n", register)),
-                Instruction::Dec(register) => assembly.push(format!("DEC {}\n", register)),
-                Instruction::Shl(register) => assembly.push(format!("SHL {}\n", register)),
-                Instruction::Shr(register) => assembly.push(format!("SHR {}\n", register)),
-                Instruction::Jump(offset) => {
-                    assembly.push(format!("JUMP {}\n", offset + assembly.len() as i64))
-                }
-                Instruction::Jpos(offset) => {
-                    assembly.push(format!("JPOS {}\n", offset + assembly.len() as i64))
-                }
-                Instruction::Jzero(offset) => {
-                    assembly.push(format!("JZERO {}\n", offset + assembly.len() as i64))
-                }
-                Instruction::Strk => {
-                    assembly.push(format!("PUT k\n")); // rx ← k
-                    assembly.push("INC k\n".to_string()); // k ← k + 1
-                }
-                Instruction::Jumpr(register) => {
-                    assembly.push(format!("GET {}\n", register)); // k ← rx
-                }
-                Instruction::Halt => assembly.push("HALT\n".to_string()),
-                Instruction::Mul => {
-                    assembly.push("PUT e\n".to_string()); // 0 1
-                    assembly.push("ADD e\n".to_string()); //2
-                    assembly.push("SUB e\n".to_string()); //3
-                    assembly.push("RST f\n".to_string()); //4
-                    assembly.push("GET c\n".to_string()); // 5
-                    assembly.push(format!("JZERO {}\n", assembly.len() + 14)); // 6
-                    assembly.push("SHR e\n".to_string());
-                    assembly.push("SHL e\n".to_string());
-                    assembly.push("GET c\n".to_string());
-                    assembly.push("SUB e\n".to_string());
-                    assembly.push(format!("JZERO {}\n", assembly.len() + 4)); // 11
-                    assembly.push("GET f\n".to_string());
-                    assembly.push("ADD b\n".to_string());
-                    assembly.push("PUT f\n".to_string());
-                    assembly.push("SHL b\n".to_string());
-                    assembly.push("SHR c\n".to_string());
-                    assembly.push("GET c\n".to_string());
-                    assembly.push("PUT e\n".to_string()); //18
-                    assembly.push(format!("JPOS {}\n", assembly.len() - 14)); // 19
-                    assembly.push("GET f\n".to_string()); // 20
-                }
-                Instruction::Div => {
-                    assembly.push("RST d\n".to_string()); // 0 1
-                    assembly.push("ADD e\n".to_string());
-                    assembly.push("SUB e\n".to_string());
-                    assembly.push(format!("JZERO {}\n", assembly.len() + 21)); // 1 2
-                    assembly.push("GET c\n".to_string()); // 2 3
-                    assembly.push("SUB b\n".to_string());
-                    assembly.push(format!("JPOS {}\n", assembly.len() + 18)); // 4 5
-                    assembly.push("GET c\n".to_string());
-                    assembly.push("PUT e\n".to_string());
-                    assembly.push("RST f\n".to_string());
-                    assembly.push("INC f\n".to_string());
-                    assembly.push("GET e\n".to_string()); // 9 10
-                    assembly.push("SUB b\n".to_string());
-                    assembly.push(format!("JPOS {}\n", assembly.len() + 10)); // 11 12
-                    assembly.push("GET b\n".to_string());
-                    assembly.push("SUB e\n".to_string());
-                    assembly.push("PUT b\n".to_string());
-                    assembly.push("GET d\n".to_string());
-                    assembly.push("ADD f\n".to_string());
-                    assembly.push("PUT d\n".to_string());
-                    assembly.push("SHL e\n".to_string());
-                    assembly.push("SHL f\n".to_string());
-                    assembly.push(format!("JPOS {}\n", assembly.len() - 11)); // 20 21
-                    assembly.push(format!("JPOS {}\n", assembly.len() - 19)); // 21 22
-                    assembly.push("GET d\n".to_string()); // 22 23
-                }
-                Instruction::Mod => {
-                    assembly.push("RST d\n".to_string()); // 0 1
-                    assembly.push("ADD e\n".to_string());
-                    assembly.push("SUB e\n".to_string());
-                    assembly.push(format!("JZERO {}\n", assembly.len() + 21)); // 1 2
-                    assembly.push("GET c\n".to_string()); // 2 3
-                    assembly.push("SUB b\n".to_string());
-                    assembly.push(format!("JPOS {}\n", assembly.len() + 19)); // 4 5
-                    assembly.push("GET c\n".to_string());
-                    assembly.push("PUT e\n".to_string());
-                    assembly.push("RST f\n".to_string());
-                    assembly.push("INC f\n".to_string());
-                    assembly.push("GET e\n".to_string()); // 9 10
-                    assembly.push("SUB b\n".to_string());
-                    assembly.push(format!("JPOS {}\n", assembly.len() + 10)); // 11 12
-                    assembly.push("GET b\n".to_string());
-                    assembly.push("SUB e\n".to_string());
-                    assembly.push("PUT b\n".to_string());
-                    assembly.push("GET d\n".to_string());
-                    assembly.push("ADD f\n".to_string());
-                    assembly.push("PUT d\n".to_string());
-                    assembly.push("SHL e\n".to_string());
-                    assembly.push("SHL f\n".to_string());
-                    assembly.push(format!("JUMP {}\n", assembly.len() - 11)); // 20 21
-                    assembly.push(format!("JUMP {}\n", assembly.len() - 19)); // 21 22
-                    assembly.push("RST b\n".to_string()); // 22 23
-                    assembly.push("GET b\n".to_string()); // 23 24
+                Instruction::Label(label) => {
+                    addresses.insert(*label, address);
                 }
+                _ => address += 1,
             }
         }
 
         let mut assembled = String::new();
-        for line in assembly {
+        for instruction in &self.pseudo_assembly {
+            let line = match instruction {
+                Instruction::Read => "READ".to_string(),
+                Instruction::Write => "WRITE".to_string(),
+                Instruction::Load(register) => format!("LOAD {}", register),
+                Instruction::Store(register) => format!("STORE {}", register),
+                Instruction::Add(register) => format!("ADD {}", register),
+                Instruction::Sub(register) => format!("SUB {}", register),
+                Instruction::Get(register) => format!("GET {}", register),
+                Instruction::Put(register) => format!("PUT {}", register),
+                Instruction::Rst(register) => format!("RST {}", register),
+                Instruction::Inc(register) => format!("INC {}", register),
+                Instruction::Dec(register) => format!("DEC {}", register),
+                Instruction::Shl(register) => format!("SHL {}", register),
+                Instruction::Shr(register) => format!("SHR {}", register),
+                Instruction::Jump(label) => format!("JUMP {}", addresses[label]),
+                Instruction::Jpos(label) => format!("JPOS {}", addresses[label]),
+                Instruction::Jzero(label) => format!("JZERO {}", addresses[label]),
+                Instruction::Strk(register) => format!("STRK {}", register),
+                Instruction::Jumpr(register) => format!("JUMPR {}", register),
+                Instruction::Halt => "HALT".to_string(),
+                Instruction::Label(_) => continue,
+            };
             assembled += &line;
+            assembled.push('\n');
         }
         assembled
-    }    pub fn construct(&mut self) -> Result<(), CompilerError>{
-        self.construct_main()?;
-        self.pseudo_assembly.push(Instruction::Halt);
-        Ok(())
     }
 
-    /// Constructs a sequence of instructions for evaluating an expression.
-    /// Supports various operations (Add, Sub, Mul, Div, Mod) by generating
-    /// the appropriate instruction sequences for each.
-    fn make_expressions(&self, expression: Expression) -> Result<Vec<Instruction>, CompilerError> {
-        match expression {
-            Expression::Value(value) => {
-                self.check_if_initialised(value.clone());
-                self.extract_value(value)
-            },
-            Expression::Add(value_0, value_1) => {
-                self.check_if_initialised(value_0.clone());
-                let mut instructions = self.extract_value(value_0)?;
-                instructions.push(Instruction::Put(B));
-                self.check_if_initialised(value_1.clone());
-                instructions.extend(self.extract_value(value_1)?);
-                instructions.push(Instruction::Add(B));
-                Ok(instructions)
-            }
-            Expression::Sub(value_0, value_1) => {
-                self.check_if_initialised(value_1.clone());
-                let mut instructions = self.extract_value(value_1)?;
-                instructions.push(Instruction::Put(B));
-                self.check_if_initialised(value_0.clone());
-                instructions.extend(self.extract_value(value_0)?);
-                instructions.push(Instruction::Sub(B));
-                Ok(instructions)
-            }
-            Expression::Mul(value_0, value_1) => {
-                self.check_if_initialised(value_0.clone());
-                let mut instructions = self.extract_value(value_0)?;
-                instructions.push(Instruction::Put(B));
-                self.check_if_initialised(value_1.clone());
-                instructions.extend(self.extract_value(value_1)?);
-                instructions.push(Instruction::Put(C));
-                instructions.push(Instruction::Mul);
-                Ok(instructions)
-            }
-            Expression::Div(value_0, value_1) => {
-                self.check_if_initialised(value_0.clone());
-                self.check_if_initialised(value_1.clone());
-                let mut instructions = self.extract_value(value_0)?;
-                instructions.push(Instruction::Put(B));
-                instructions.extend(self.extract_value(value_1)?);
-                instructions.push(Instruction::Put(C));
-                instructions.push(Instruction::Div);
-                Ok(instructions)
-            }
-            Expression::Mod(value_0, value_1) => {
-                self.check_if_initialised(value_0.clone());
-                self.check_if_initialised(value_1.clone());
-                let mut instructions = self.extract_value(value_0)?;
-                instructions.push(Instruction::Put(B));
-                instructions.extend(self.extract_value(value_1)?);
-                instructions.push(Instruction::Put(C));
-                instructions.push(Instruction::Mod);
-                Ok(instructions)
+    /// Generates code for the whole program, which the checker has found free of semantic
+    /// errors.
+    ///
+    /// The main program is first translated to three-address code ending with `Halt`,
+    /// followed by every procedure called out of line from it; the result is then
+    /// optimised, lowered to instructions and cleaned up by the peephole pass.
+    pub fn construct(&mut self) {
+        let mut call_sites = HashMap::new();
+        count_call_sites(&self.ast.1 .1, &mut call_sites);
+        for builder in self.procedures.values() {
+            count_call_sites(&builder.commands, &mut call_sites);
+        }
+        self.call_sites = call_sites;
+        self.layout = layout::plan(&self.ast.1 .1, self.memory_pointer, &self.call_policy());
+
+        self.construct_main();
+        self.program.code.push(Instr::Halt);
+        // Compiling a body may call further procedures out of line, which extends the list.
+        let mut next = 0;
+        while next < self.out_of_line.len() {
+            let procedure = self.out_of_line[next].clone();
+            self.construct_procedure(&procedure);
+            next += 1;
+        }
+        ir::fold::fold_constants(&mut self.program);
+        ir::dead::eliminate_dead_stores(&mut self.program);
+        self.layout.footprint += ir::fold::pool_constants(&mut self.program, self.layout.footprint);
+        let lowered = lower::lower(&self.program, self.options.routines);
+        (self.pseudo_assembly, self.peephole_savings) = peephole::optimize(lowered);
+    }
+
+    /// Number of memory cells the generated program uses.
+    pub fn memory_footprint(&self) -> u64 {
+        self.layout.footprint
+    }
+
+    /// Number of instructions the peephole pass removed.
+    pub fn peephole_savings(&self) -> usize {
+        self.peephole_savings
+    }
+
+    fn call_policy(&self) -> CallPolicy<'_> {
+        CallPolicy {
+            mode: self.options.inline,
+            procedures: &self.procedures,
+            symbols: &self.symbols,
+            call_sites: &self.call_sites,
+        }
+    }
+
+    fn new_temp(&mut self) -> ir::Temp {
+        self.program.new_temp()
+    }
+
+    /// Reserves the return address cell, one address cell per parameter and the locals of a procedure.
+    fn allocate_frame(&mut self, procedure: &str) {
+        let scope = self.symbols.procedures[procedure].scope.clone();
+        let mut pointer = self.layout.frames[procedure];
+        let return_slot = pointer;
+        pointer += 1;
+        let mut parameter_slots = Vec::new();
+        let mut bindings = Bindings::new();
+        for symbol in scope.parameters() {
+            let binding = match symbol.kind {
+                SymbolKind::ArrayParameter => VariableVariant::TableReference(pointer),
+                _ => VariableVariant::AtomicReference(pointer),
+            };
+            bindings.insert(symbol.name.clone(), binding);
+            parameter_slots.push(pointer);
+            pointer += 1;
+        }
+        for symbol in scope.locals() {
+            let name = symbol.name.clone();
+            match symbol.kind {
+                SymbolKind::Array(length) => {
+                    bindings.insert(name, VariableVariant::Table(pointer, length));
+                    self.program.arrays.push(pointer..pointer + length);
+                    pointer += length;
+                }
+                _ => {
+                    bindings.insert(name, VariableVariant::Atomic(pointer));
+                    pointer += 1;
+                }
             }
         }
+        let entry = self.program.new_label();
+        self.out_of_line.push(procedure.to_string());
+        let frame = ProcedureFrame { entry, return_slot, parameter_slots, end: pointer, bindings };
+        self.frames.insert(procedure.to_string(), frame);
+    }
+
+    /// Compiles a procedure body once, between `Enter`, which saves the return address
+    /// left in H by `Instruction::Call`, and `Return`, which jumps back to it.
+    fn construct_procedure(&mut self, procedure: &str) {
+        let frame = self.frames[procedure].clone();
+        let mut code = vec![Instr::Label(frame.entry), Instr::Enter(frame.return_slot)];
+
+        self.memory_pointer = frame.end;
+        self.environment.push(frame.bindings);
+        let commands = self.procedures[procedure].commands.clone();
+        code.extend(self.make_commands(commands));
+        self.environment.pop();
+
+        code.push(Instr::Return(frame.return_slot));
+        self.program.code.extend(code);
     }
 
-    /// Generates instructions to load the address of a variable into a register.
+    /// Binding of a variable in the current scope. The checker has resolved every name
+    /// before code generation starts, so a missing one is a bug in the compiler.
+    fn binding(&self, name: &str) -> VariableVariant {
+        *self.environment.get(name).unwrap_or_else(|| panic!("variable `{}` is not bound", name))
+    }
+
+    /// Generates code for evaluating an expression, returning the operand holding its value.
+    /// Subtraction evaluates its right operand first, so that the left one ends up in A.
+    fn make_expressions(&mut self, expression: Expression) -> (Vec<Instr>, Operand) {
+        let (operation, value_0, value_1) = match expression {
+            Expression::Value(value) => return self.value(value),
+            Expression::Add(value_0, value_1) => (BinaryOp::Add, value_0, value_1),
+            Expression::Sub(value_0, value_1) => (BinaryOp::Sub, value_0, value_1),
+            Expression::Mul(value_0, value_1) => (BinaryOp::Mul, value_0, value_1),
+            Expression::Div(value_0, value_1) => (BinaryOp::Div, value_0, value_1),
+            Expression::Mod(value_0, value_1) => (BinaryOp::Mod, value_0, value_1),
+        };
+        let (code, left, right) = self.value_pair(value_0, value_1, operation == BinaryOp::Sub);
+        let temp = self.new_temp();
+        let mut code = code;
+        code.push(Instr::Binary(temp, operation, left, right));
+        (code, Operand::Temp(temp))
+    }
+
+    /// Generates code for two values, evaluating the right one first if asked to.
+    fn value_pair(&mut self, value_0: Value, value_1: Value, right_first: bool) -> (Vec<Instr>, Operand, Operand) {
+        if right_first {
+            let (mut code, right) = self.value(value_1);
+            let (left_code, left) = self.value(value_0);
+            code.extend(left_code);
+            (code, left, right)
+        } else {
+            let (mut code, left) = self.value(value_0);
+            let (right_code, right) = self.value(value_1);
+            code.extend(right_code);
+            (code, left, right)
+        }
+    }
+
+    /// Generates code for finding the memory cell of a variable.
     /// Handles different types of identifiers: base, numerically indexed, and procedurally indexed.
-    fn load_variable_address(&self, identifier: Identifier) -> Result<Vec<Instruction>, CompilerError> {
+    fn variable_address(&mut self, identifier: Identifier) -> (Vec<Instr>, Address) {
         match identifier {
             Identifier::Base(id) => self.access_common_variable(id),
-            Identifier::NumIndexed(id, num) => self.access_array_element(id, num as usize),
+            Identifier::NumIndexed(id, num) => self.access_array_element(id, num),
             Identifier::PidIndexed(id, index_id) => self.access_dynamic_index_element(id, index_id),
         }
     }
 
-    /// Generates instructions for accessing a base identifier's value.
-    /// It checks if the identifier is a simple variable and returns instructions
-    /// to put its memory address in a register, handling undeclared and incorrectly used variables.
-    fn access_common_variable(&self, id: (String, usize)) -> Result<Vec<Instruction>, CompilerError> {
-        let variable = self.memory.get(&id.0)
-            .ok_or(CompilerError::UndeclaredVariable(id.0.clone(), id.1))?;
-        match variable {
-            VariableVariant::Atomic(pointer) => Ok(put_in_a(*pointer)),
-            VariableVariant::Table(_, _) => Err(CompilerError::IncorrectUseOfVariable(id.0, id.1)),
+    /// Generates code for finding a base identifier's cell.
+    fn access_common_variable(&mut self, id: (String, usize)) -> (Vec<Instr>, Address) {
+        match self.binding(&id.0) {
+            VariableVariant::Atomic(pointer) => (vec![], Address::Direct(pointer)),
+            VariableVariant::AtomicReference(slot) => {
+                let temp = self.new_temp();
+                (vec![Instr::Load(temp, Address::Direct(slot))], Address::Indirect(temp))
+            }
+            VariableVariant::Table(_, _) | VariableVariant::TableReference(_) => {
+                unreachable!("array `{}` used as a variable", id.0)
+            }
         }
     }
 
-    /// Generates instructions for accessing an element of an array by a numerical index.
-    /// It calculates the memory address of the element and handles errors like undeclared variables
-    /// or index out of bounds.
-    fn access_array_element(&self, id: (String, usize), num: usize) -> Result<Vec<Instruction>, CompilerError> {
-        let variable = self.memory.get(&id.0)
-            .ok_or(CompilerError::UndeclaredVariable(id.0.clone(), id.1))?;
-        match variable {
-            VariableVariant::Atomic(_) => Err(CompilerError::IncorrectUseOfVariable(id.0, id.1)),
+    /// Generates code for finding an element of an array by a numerical index.
+    fn access_array_element(&mut self, id: (String, usize), num: Num) -> (Vec<Instr>, Address) {
+        match self.binding(&id.0) {
+            VariableVariant::Atomic(_) | VariableVariant::AtomicReference(_) => {
+                unreachable!("variable `{}` used as an array", id.0)
+            }
             VariableVariant::Table(pointer, size) => {
-                if num >= *size as usize {
-                    Err(CompilerError::IndexOutOfBounds(id.0, id.1))
-                } else {
-                    Ok(put_in_a(pointer + num as u64))
+                debug_assert!(num < size, "index {} out of bounds of array `{}`", num, id.0);
+                (vec![], Address::Direct(pointer + num))
+            }
+            // The size of an array passed by reference is not known here.
+            VariableVariant::TableReference(slot) => {
+                let base = self.new_temp();
+                let mut code = vec![Instr::Load(base, Address::Direct(slot))];
+                if num == 0 {
+                    return (code, Address::Indirect(base));
                 }
+                let element = self.new_temp();
+                code.push(Instr::Binary(element, BinaryOp::Add, Operand::Temp(base), Operand::Const(Literal::from(num))));
+                (code, Address::Indirect(element))
             }
         }
     }
 
-    /// Generates instructions for accessing an array element indexed by another variable.
-    /// It calculates the element's memory address using the index variable's value,
-    /// handling various errors such as undeclared variables or using an array as an index.
-    fn access_dynamic_index_element(&self, id: (String, usize), index_id: (String, usize)) -> Result<Vec<Instruction>, CompilerError> {
-        if !self.initialisated_variables.contains(&index_id.0) {
-            let warning_id = index_id.0.split('@').next().unwrap().to_string();
-            println!("Warning: Variable {} used before initialisation", warning_id);
-        }
+    /// Generates code for finding an array element indexed by another variable.
+    fn access_dynamic_index_element(&mut self, id: (String, usize), index_id: (String, usize)) -> (Vec<Instr>, Address) {
+        let (mut code, index_address) = self.access_common_variable(index_id);
+        let index = self.new_temp();
+        code.push(Instr::Load(index, index_address));
 
-        let variable = self.memory.get(&index_id.0)
-            .ok_or(CompilerError::UndeclaredVariable(index_id.0.clone(), index_id.1))?;
-        let mut instructions = match variable {
-            VariableVariant::Atomic(pointer) => put_in_a(*pointer),
-            VariableVariant::Table(_, _) => return Err(CompilerError::ArrayUsedAsIndex(id.0, id.1)),
+        let base = match self.binding(&id.0) {
+            VariableVariant::Atomic(_) | VariableVariant::AtomicReference(_) => {
+                unreachable!("variable `{}` used as an array", id.0)
+            }
+            VariableVariant::Table(pointer, _) => Operand::Const(Literal::from(pointer)),
+            VariableVariant::TableReference(slot) => {
+                let base = self.new_temp();
+                code.push(Instr::Load(base, Address::Direct(slot)));
+                Operand::Temp(base)
+            }
         };
+        let element = self.new_temp();
+        code.push(Instr::Binary(element, BinaryOp::Add, Operand::Temp(index), base));
+        (code, Address::Indirect(element))
+    }
 
-        instructions.push(Instruction::Load(A));
-        instructions.push(Instruction::Put(H));
+    /// Generates a call of a procedure compiled out of line: stores the addresses of the
+    /// arguments in the callee's parameter slots and jumps to it.
+    fn call_procedure(&mut self, procedure: &str, arguments: &Arguments) -> Vec<Instr> {
+        if !self.frames.contains_key(procedure) {
+            self.allocate_frame(procedure);
+        }
+        let frame = self.frames[procedure].clone();
+        let mut code = Vec::new();
+        for (argument, slot) in arguments.iter().zip(&frame.parameter_slots) {
+            let (argument_code, address) = self.argument_address(argument);
+            code.extend(argument_code);
+            code.push(Instr::Store(Address::Direct(*slot), address));
+        }
+        code.push(Instr::Call(frame.entry));
+        code
+    }
 
-        match self.memory.get(&id.0).unwrap() {
-            VariableVariant::Atomic(_) => Err(CompilerError::IncorrectUseOfVariable(id.0, id.1)),
-            VariableVariant::Table(pointer, _) => {
-                instructions.extend(put_in_a(*pointer));
-                instructions.push(Instruction::Add(H));
-                Ok(instructions)
-            }
+    /// Generates a copy of the procedure body in a scope of its own, with parameters bound
+    /// to the caller's variables and locals placed above the memory in use. The locals are
+    /// released after the body.
+    fn inline_procedure(&mut self, procedure: &str, arguments: &Arguments) -> Vec<Instr> {
+        let scope = self.symbols.procedures[procedure].scope.clone();
+        let memory_pointer = self.memory_pointer;
+        let mut bindings = Bindings::new();
+        for (argument, parameter) in arguments.iter().zip(scope.parameters()) {
+            bindings.insert(parameter.name.clone(), self.binding(&argument.0));
+        }
+        for symbol in scope.locals() {
+            let binding = match symbol.kind {
+                SymbolKind::Array(length) => {
+                    let cells = self.memory_pointer..self.memory_pointer + length;
+                    if !self.program.arrays.contains(&cells) {
+                        self.program.arrays.push(cells);
+                    }
+                    self.memory_pointer += length;
+                    VariableVariant::Table(self.memory_pointer - length, length)
+                }
+                _ => {
+                    self.memory_pointer += 1;
+                    VariableVariant::Atomic(self.memory_pointer - 1)
+                }
+            };
+            bindings.insert(symbol.name.clone(), binding);
         }
+
+        self.environment.push(bindings);
+        let commands = self.procedures[procedure].commands.clone();
+        let code = self.make_commands(commands);
+        self.environment.pop();
+        self.memory_pointer = memory_pointer;
+        code
     }
-    fn extract_value(&self, value: Value) -> Result<Vec<Instruction>, CompilerError> {
-        match value {
-            Value::Num(num) => Ok(put_in_a(num)),
-            Value::Id(identifier) => {
-                let mut sub_instructions = self.load_variable_address(identifier)?;
-                sub_instructions.push(Instruction::Load(A));
-                Ok(sub_instructions)
+
+    /// Generates code for the address passed for a by-reference argument: the variable
+    /// itself, or the address held by a parameter of the calling procedure.
+    fn argument_address(&mut self, argument: &(String, usize)) -> (Vec<Instr>, Operand) {
+        match self.binding(&argument.0) {
+            VariableVariant::Atomic(pointer) | VariableVariant::Table(pointer, _) => {
+                (vec![], Operand::Const(Literal::from(pointer)))
+            }
+            VariableVariant::AtomicReference(slot) | VariableVariant::TableReference(slot) => {
+                let temp = self.new_temp();
+                (vec![Instr::Load(temp, Address::Direct(slot))], Operand::Temp(temp))
             }
         }
     }
-    fn check_if_initialised(&self, value: Value) {
+
+    /// Generates code for reading a value, returning the operand holding it.
+    fn value(&mut self, value: Value) -> (Vec<Instr>, Operand) {
         match value {
-            Value::Num(_) => {},
+            Value::Num(num) => (vec![], Operand::Const(num)),
             Value::Id(identifier) => {
-                let id = match identifier.clone() {
-                    Identifier::Base(id) => id,
-                    Identifier::NumIndexed(id, _) => id,
-                    Identifier::PidIndexed(id, _) => id,
-                };
-                if !self.initialisated_variables.contains(&id.0) {
-                    let id_for_warning = id.0.split('@').next().unwrap().to_string();
-                    println!("Warning: Variable {} used before initialisation", id_for_warning)
-                }
-            },
+                let (mut code, address) = self.variable_address(identifier);
+                let temp = self.new_temp();
+                code.push(Instr::Load(temp, address));
+                (code, Operand::Temp(temp))
+            }
         }
     }
-    fn construct_main(&mut self) -> Result<(), CompilerError> {
-        let commands = self.ast.1 .1.clone();
 
-        let constructed_commands: Vec<Instruction> = commands
-            .into_iter()
-            .map(|command| self.make_instructions_list(command))
-            .collect::<Result<Vec<_>, _>>()?
-            .into_iter()
-            .flatten()
-            .collect();
+    /// Generates a branch to `target` taken when the condition holds. The operand that
+    /// the comparison subtracts from is evaluated last.
+    fn branch(&mut self, condition: Condition, negate: bool, target: ir::Label) -> Vec<Instr> {
+        let (relation, value_0, value_1) = match condition {
+            Condition::Equal(value_0, value_1) => (Relation::Equal, value_0, value_1),
+            Condition::NotEqual(value_0, value_1) => (Relation::NotEqual, value_0, value_1),
+            Condition::Greater(value_0, value_1) => (Relation::Greater, value_0, value_1),
+            Condition::Lower(value_0, value_1) => (Relation::Lower, value_0, value_1),
+            Condition::GreaterOrEqual(value_0, value_1) => (Relation::GreaterOrEqual, value_0, value_1),
+            Condition::LowerOrEqual(value_0, value_1) => (Relation::LowerOrEqual, value_0, value_1),
+        };
+        let relation = if negate { relation.negate() } else { relation };
+        let (mut code, left, right) = self.value_pair(value_0, value_1, relation.subtracts_right());
+        code.push(Instr::Branch(relation, left, right, target));
+        code
+    }
 
-        self.pseudo_assembly.extend(constructed_commands);
+    fn construct_main(&mut self) {
+        let commands = self.ast.1 .1.clone();
+        let code = self.make_commands(commands);
+        self.program.code.extend(code);
+    }
 
-        Ok(())
+    /// Generates code for a list of commands.
+    fn make_commands(&mut self, commands: Commands) -> Vec<Instr> {
+        let mut code = Vec::new();
+        for command in commands {
+            code.extend(self.make_instructions_list(command));
+        }
+        code
     }
 
-    /// Constructs a sequence of instructions from a given command.
-    /// This function handles different types of commands (e.g., Assign, If, While, Repeat, ProcCall, Read, Write)
-    /// by converting them into a list of pseudo-assembly instructions.
-    fn make_instructions_list(&mut self, command: Command) -> Result<Vec<Instruction>, CompilerError> {
+    /// Generates three-address code for a command.
+    /// This function handles different types of commands (e.g., Assign, If, While, Repeat, ProcCall, Read, Write).
+    fn make_instructions_list(&mut self, command: Command) -> Vec<Instr> {
         match command {
             Command::Assign(identifier, expression) => {
-                let mut instructions: Vec<Instruction> = Vec::new();
-                let id = match identifier.clone() {
-                    Identifier::Base(id) => id,
-                    Identifier::NumIndexed(id, _) => id,
-                    Identifier::PidIndexed(id, _) => id,
-                };
-                self.initialisated_variables.insert(id.0.clone());
-                instructions.extend(self.load_variable_address(identifier)?);
-                instructions.push(Instruction::Put(G));
-                instructions.extend(self.make_expressions(expression)?);
-                instructions.push(Instruction::Store(G));
-                Ok(instructions)
+                let (mut code, address) = self.variable_address(identifier);
+                let (expression_code, value) = self.make_expressions(expression);
+                code.extend(expression_code);
+                code.push(Instr::Store(address, value));
+                code
             }
             Command::If(condition, commands, else_commands) => {
-                let mut instructions: Vec<Instruction> = Vec::new();
-                let mut sub_instuctions: Vec<Instruction> = Vec::new();
-                for command in commands {
-                    sub_instuctions.extend(self.make_instructions_list(command)?);
-                }
-                let sub_instructions_length: u64 = sub_instuctions.iter().map(|i| i.len()).sum();
-                let mut sub_else_instuctions: Vec<Instruction> = Vec::new();
-                if let Some(else_commands) = else_commands {
-                    for command in else_commands {
-                        sub_else_instuctions.extend(self.make_instructions_list(command)?);
-                    }
-                }
-                let sub_else_instruction_length: u64 =
-                    sub_else_instuctions.iter().map(|i| i.len()).sum();
-                match condition {
-                    Condition::Equal(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(C));
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_instructions_length as i64 + 5));
-                        cond_instructions.push(Instruction::Get(B));
-                        cond_instructions.push(Instruction::Sub(C));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_instructions_length as i64 + 2));
-
-                        instructions.extend(cond_instructions);
-                        instructions.extend(sub_instuctions);
-                        instructions
-                            .push(Instruction::Jump(sub_else_instruction_length as i64 + 1));
-                        instructions.extend(sub_else_instuctions);
-                    }
-                    Condition::NotEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(C));
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_else_instruction_length as i64 + 5));
-                        cond_instructions.push(Instruction::Get(B));
-                        cond_instructions.push(Instruction::Sub(C));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_else_instruction_length as i64 + 2));
-
-                        instructions.extend(cond_instructions);
-                        instructions.extend(sub_else_instuctions);
-                        instructions.push(Instruction::Jump(sub_instructions_length as i64 + 1));
-                        instructions.extend(sub_instuctions);
-                    }
-                    Condition::Greater(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_else_instruction_length as i64 + 2));
-
-                        instructions.extend(cond_instructions);
-                        instructions.extend(sub_else_instuctions);
-                        instructions.push(Instruction::Jump(sub_instructions_length as i64 + 1));
-                        instructions.extend(sub_instuctions);
-                    }
-                    Condition::Lower(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_else_instruction_length as i64 + 2));
-
-                        instructions.extend(cond_instructions);
-                        instructions.extend(sub_else_instuctions);
-                        instructions.push(Instruction::Jump(sub_instructions_length as i64 + 1));
-                        instructions.extend(sub_instuctions);
-                    }
-                    Condition::GreaterOrEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_instructions_length as i64 + 2));
-
-                        instructions.extend(cond_instructions);
-                        instructions.extend(sub_instuctions);
-                        instructions
-                            .push(Instruction::Jump(sub_else_instruction_length as i64 + 1));
-                        instructions.extend(sub_else_instuctions);
-                    }
-                    Condition::LowerOrEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_instructions_length as i64 + 2));
-
-                        instructions.extend(cond_instructions);
-                        instructions.extend(sub_instuctions);
-                        instructions
-                            .push(Instruction::Jump(sub_else_instruction_length as i64 + 1));
-                        instructions.extend(sub_else_instuctions);
+                let otherwise = self.program.new_label();
+                let mut code = self.branch(condition, true, otherwise);
+                code.extend(self.make_commands(commands));
+                match else_commands {
+                    Some(else_commands) => {
+                        let end = self.program.new_label();
+                        code.push(Instr::Jump(end));
+                        code.push(Instr::Label(otherwise));
+                        code.extend(self.make_commands(else_commands));
+                        code.push(Instr::Label(end));
                     }
+                    None => code.push(Instr::Label(otherwise)),
                 }
-                Ok(instructions)
+                code
             }
             Command::While(condition, commands) => {
-                let mut instructions: Vec<Instruction> = Vec::new();
-                let mut sub_instuctions: VecDeque<Instruction> = VecDeque::new();
-                for command in commands {
-                    sub_instuctions.extend(self.make_instructions_list(command)?);
-                }
-                let sub_instructions_length: u64 = sub_instuctions.iter().map(|i| i.len()).sum();
-                let cond_instructions = match condition {
-                    Condition::Equal(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(C));
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_instructions_length as i64 + 5));
-                        cond_instructions.push(Instruction::Get(B));
-                        cond_instructions.push(Instruction::Sub(C));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_instructions_length as i64 + 2));
-                        cond_instructions
-                    }
-                    Condition::NotEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(C));
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions.push(Instruction::Jpos(5));
-                        cond_instructions.push(Instruction::Get(B));
-                        cond_instructions.push(Instruction::Sub(C));
-                        cond_instructions.push(Instruction::Jpos(2));
-                        cond_instructions
-                            .push(Instruction::Jump(sub_instructions_length as i64 + 2));
-                        cond_instructions
-                    }
-                    Condition::Greater(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions.push(Instruction::Jpos(2));
-                        cond_instructions
-                            .push(Instruction::Jump(sub_instructions_length as i64 + 2));
-                        cond_instructions
-                    }
-                    Condition::Lower(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions.push(Instruction::Jpos(2));
-                        cond_instructions
-                            .push(Instruction::Jump(sub_instructions_length as i64 + 2));
-                        cond_instructions
-                    }
-                    Condition::GreaterOrEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_instructions_length as i64 + 2));
-                        cond_instructions
-                    }
-                    Condition::LowerOrEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions
-                            .push(Instruction::Jpos(sub_instructions_length as i64 + 2));
-                        cond_instructions
-                    }
-                };
-                let cond_instructions_length: u64 = cond_instructions.iter().map(|i| i.len()).sum();
-                instructions.extend(cond_instructions);
-                instructions.extend(sub_instuctions);
-                instructions.push(Instruction::Jump(
-                    -((sub_instructions_length + cond_instructions_length) as i64),
-                ));
-                Ok(instructions)
+                let (start, end) = (self.program.new_label(), self.program.new_label());
+                let mut code = vec![Instr::Label(start)];
+                code.extend(self.branch(condition, true, end));
+                self.loop_depth += 1;
+                code.extend(self.make_commands(commands));
+                self.loop_depth -= 1;
+                code.push(Instr::Jump(start));
+                code.push(Instr::Label(end));
+                code
             }
             Command::Repeat(commands, condition) => {
-                let mut instructions: Vec<Instruction> = Vec::new();
-                let mut sub_instuctions: VecDeque<Instruction> = VecDeque::new();
-                for command in commands {
-                    sub_instuctions.extend(self.make_instructions_list(command)?);
-                }
-                let sub_instructions_length: u64 = sub_instuctions.iter().map(|i| i.len()).sum();
-
-                let cond_instructions = match condition {
-                    Condition::Equal(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(C));
-                        cond_instructions.push(Instruction::Sub(B));
-                        let cond_instructions_length: u64 =
-                            cond_instructions.iter().map(|i| i.len()).sum();
-                        cond_instructions.push(Instruction::Jpos(
-                            -((cond_instructions_length + sub_instructions_length) as i64),
-                        ));
-                        cond_instructions.push(Instruction::Get(B));
-                        cond_instructions.push(Instruction::Sub(C));
-                        cond_instructions.push(Instruction::Jpos(
-                            -((cond_instructions_length + sub_instructions_length + 3) as i64),
-                        ));
-                        cond_instructions
-                    }
-                    Condition::NotEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(C));
-                        cond_instructions.push(Instruction::Sub(B));
-                        let cond_instructions_length: u64 =
-                            cond_instructions.iter().map(|i| i.len()).sum();
-                        cond_instructions.push(Instruction::Jpos(5));
-                        cond_instructions.push(Instruction::Get(B));
-                        cond_instructions.push(Instruction::Sub(C));
-                        cond_instructions.push(Instruction::Jpos(2));
-                        cond_instructions.push(Instruction::Jump(
-                            -((cond_instructions_length + sub_instructions_length) as i64),
-                        ));
-                        cond_instructions
-                    }
-                    Condition::Greater(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions.push(Instruction::Jpos(2));
-                        let cond_instructions_length: u64 =
-                            cond_instructions.iter().map(|i| i.len()).sum();
-                        cond_instructions.push(Instruction::Jump(
-                            -((cond_instructions_length + sub_instructions_length) as i64),
-                        ));
-                        cond_instructions
-                    }
-                    Condition::Lower(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        cond_instructions.push(Instruction::Jpos(2));
-                        let cond_instructions_length: u64 =
-                            cond_instructions.iter().map(|i| i.len()).sum();
-                        cond_instructions.push(Instruction::Jump(
-                            -((cond_instructions_length + sub_instructions_length) as i64),
-                        ));
-                        cond_instructions
-                    }
-                    Condition::GreaterOrEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        let cond_instructions_length: u64 =
-                            cond_instructions.iter().map(|i| i.len()).sum();
-                        cond_instructions.push(Instruction::Jpos(
-                            -((cond_instructions_length + sub_instructions_length) as i64),
-                        ));
-                        cond_instructions
-                    }
-                    Condition::LowerOrEqual(value_0, value_1) => {
-                        let mut cond_instructions: Vec<Instruction> = Vec::new();
-                        cond_instructions.extend(self.extract_value(value_1)?);
-                        cond_instructions.push(Instruction::Put(B));
-                        cond_instructions.extend(self.extract_value(value_0)?);
-                        cond_instructions.push(Instruction::Sub(B));
-                        let cond_instructions_length: u64 =
-                            cond_instructions.iter().map(|i| i.len()).sum();
-                        cond_instructions.push(Instruction::Jpos(
-                            -((cond_instructions_length + sub_instructions_length) as i64),
-                        ));
-                        cond_instructions
-                    }
-                };
-                instructions.extend(sub_instuctions);
-                instructions.extend(cond_instructions);
-                Ok(instructions)
+                let start = self.program.new_label();
+                let mut code = vec![Instr::Label(start)];
+                self.loop_depth += 1;
+                code.extend(self.make_commands(commands));
+                self.loop_depth -= 1;
+                code.extend(self.branch(condition, true, start));
+                code
             }
             Command::ProcCall((procedure_id, arguments)) => {
-                let mut instructions: Vec<Instruction> = Vec::new();
-                let ids: Vec<String> = arguments.iter().map(|arg| arg.0.clone()).collect();
-                for id in ids {
-                    if  (&id).contains(&format!("@{}", procedure_id.0)) {
-                        return Err(CompilerError::RecursiveProcedureCall(procedure_id.0, procedure_id.1));
-                    }
-                }
-
-                let builder = self.procedures.clone().get(&procedure_id.0).ok_or(CompilerError::UndeclaredProcedure(procedure_id.0.clone(), procedure_id.1))?.clone();
-                if builder.declared_arguments.len() != arguments.len() {
-                    return Err(CompilerError::WrongNumberOfArguments(procedure_id.0.clone(), procedure_id.1));
-                }
-                if let Some(declarations) = &builder.declarations {
-                    for declaration in declarations {
-                        match declaration {
-                            DeclarationVariant::Base(id) => {
-                                self.memory.insert(format!("{}@{}", id.0, procedure_id.0), VariableVariant::Atomic(self.memory_pointer));
-                                self.memory_pointer += 1;
-                            },
-                            DeclarationVariant::NumIndexed(id, length) => {
-                                self.memory.insert(format!("{}@{}", id.0, procedure_id.0), VariableVariant::Table(self.memory_pointer, *length));
-                                self.memory_pointer += length;
-                            },
-                        }
-                    }
-                }
-
-                for (argument, declared_argument) in arguments.iter().zip(&builder.declared_arguments) {
-                    if let Some(declarations) = &builder.declarations{
-                        for declaration in declarations {
-                            let id = match declaration {
-                                DeclarationVariant::Base(id) => id,
-                                DeclarationVariant::NumIndexed(id, _) => id,
-                            };
-                            let arg_id = match declared_argument {
-                                ArgumentsDeclarationVariant::Base(id) => id,
-                                ArgumentsDeclarationVariant::Table(id) => id,
-                            };
-                            if id.0 == arg_id.0 {
-                                return Err(CompilerError::DuplicateVariableDeclaration(id.0.clone(), id.1));
-                            }
-                        }
-                    }
-                    let pointee = self.memory.get(argument.0.as_str()).unwrap();
-                    match declared_argument {
-                        ArgumentsDeclarationVariant::Base(id) => {
-                            match pointee {
-                                VariableVariant::Atomic(pointer) => {
-                                    self.initialisated_variables.insert(argument.0.clone());
-                                    self.memory.insert(format!("{}@{}", id.0, procedure_id.0), VariableVariant::Atomic(*pointer));
-                                    self.initialisated_variables.insert(format!("{}@{}", id.0, procedure_id.0));
-                                },
-                                VariableVariant::Table(_, _) => return Err(CompilerError::WrongArgumentType(id.0.clone(), id.1)),
-                            }
-                        },
-                        ArgumentsDeclarationVariant::Table(id) => {
-                            match pointee {
-                                VariableVariant::Atomic(_) => return Err(CompilerError::WrongArgumentType(id.0.clone(), id.1)),
-                                VariableVariant::Table(start, size) => {
-                                    self.initialisated_variables.insert(argument.0.clone());
-                                    self.memory.insert(format!("{}@{}", id.0, procedure_id.0), VariableVariant::Table(*start, *size));
-                                    self.initialisated_variables.insert(format!("{}@{}", id.0, procedure_id.0));
-                                },
-                            }
-                        },
-                    }
-                }
-                for command in &builder.commands {
-                    instructions.extend(self.make_instructions_list(command.clone())?);
+                if self.call_policy().inline_call(&procedure_id.0, arguments.len(), self.loop_depth) {
+                    self.inline_procedure(&procedure_id.0, &arguments)
+                } else {
+                    self.call_procedure(&procedure_id.0, &arguments)
                 }
-                Ok(instructions)
             }
             Command::Read(identifier) => {
-                let id = match identifier.clone() {
-                    Identifier::Base(id) => id,
-                    Identifier::NumIndexed(id, _) => id,
-                    Identifier::PidIndexed(id, _) => id,
-                };
-                self.initialisated_variables.insert(id.0.clone());
-                let mut instructions: Vec<Instruction> = Vec::new();
-                instructions.extend(self.load_variable_address(identifier)?);
-                instructions.push(Instruction::Put(G));
-                instructions.push(Instruction::Read);
-                instructions.push(Instruction::Store(G));
-                Ok(instructions)
+                let (mut code, address) = self.variable_address(identifier);
+                let temp = self.new_temp();
+                code.push(Instr::Read(temp));
+                code.push(Instr::Store(address, Operand::Temp(temp)));
+                code
             }
             Command::Write(value) => {
-                let mut instructions: Vec<Instruction> = self.extract_value(value)?;
-                instructions.push(Instruction::Write);
-                Ok(instructions)
+                let (mut code, value) = self.value(value);
+                code.push(Instr::Write(value));
+                code
             }
         }
     }
-
 }
-
-
@@ -0,0 +1,271 @@
+//! Peephole optimisation of the final instruction stream, before labels are resolved.
+//!
+//! Register contents are followed through straight-line code by value numbering: a copy
+//! to a register already holding the value, a constant rebuilt in a register that holds
+//! it, or a LOAD of a cell whose value A already holds does nothing and is removed. Jumps
+//! to the next instruction and code after an unconditional jump are removed as well.
+//!
+//! Knowledge is dropped at labels and after unconditional jumps, as control may arrive
+//! there from elsewhere: the instruction after the JUMP of a call is where it returns.
+
+use std::collections::HashMap;
+
+use crate::emitter::instruct::Instruction::{self, *};
+use crate::emitter::Registers::{self, *};
+
+/// What a register or memory cell is known to hold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Value {
+    Unknown,
+    Const(u64),
+    /// A value computed at run time, equal to every other holder of the same number.
+    Computed(usize),
+}
+
+fn index(register: Registers) -> usize {
+    register as usize
+}
+
+struct Simulation {
+    registers: [Value; 8],
+    memory: HashMap<u64, Value>,
+    next_number: usize,
+}
+
+impl Simulation {
+    fn new() -> Simulation {
+        Simulation { registers: [Value::Unknown; 8], memory: HashMap::new(), next_number: 0 }
+    }
+
+    fn forget(&mut self) {
+        self.registers = [Value::Unknown; 8];
+        self.memory.clear();
+    }
+
+    fn get(&self, register: Registers) -> Value {
+        self.registers[index(register)]
+    }
+
+    fn set(&mut self, register: Registers, value: Value) {
+        self.registers[index(register)] = value;
+    }
+
+    fn computed(&mut self) -> Value {
+        self.next_number += 1;
+        Value::Computed(self.next_number)
+    }
+
+    /// Known and equal values; two unknown values are never equal.
+    fn same(left: Value, right: Value) -> bool {
+        left != Value::Unknown && left == right
+    }
+
+    /// Applies an operation on a constant, giving a new computed value when unknown.
+    fn apply(&mut self, register: Registers, operation: impl Fn(u64) -> Option<u64>) {
+        let value = match self.get(register) {
+            Value::Const(value) => operation(value).map(Value::Const),
+            _ => None,
+        };
+        let value = value.unwrap_or_else(|| self.computed());
+        self.set(register, value);
+    }
+
+    /// Whether the instruction leaves everything as it is; otherwise records its effect.
+    fn redundant(&mut self, instruction: &Instruction) -> bool {
+        match *instruction {
+            Get(register) if Self::same(self.get(A), self.get(register)) => return true,
+            Put(register) if Self::same(self.get(A), self.get(register)) => return true,
+            Load(register) => {
+                if let Value::Const(address) = self.get(register) {
+                    let cell = self.memory.get(&address).copied().unwrap_or(Value::Unknown);
+                    if Self::same(cell, self.get(A)) {
+                        return true;
+                    }
+                    let value = if cell == Value::Unknown { self.computed() } else { cell };
+                    self.memory.insert(address, value);
+                    self.set(A, value);
+                    return false;
+                }
+            }
+            _ => {}
+        }
+        match *instruction {
+            Get(register) => self.set(A, self.get(register)),
+            Put(register) => self.set(register, self.get(A)),
+            Load(_) | Read => {
+                let value = self.computed();
+                self.set(A, value);
+            }
+            Store(register) => {
+                if self.get(A) == Value::Unknown {
+                    let value = self.computed();
+                    self.set(A, value);
+                }
+                match self.get(register) {
+                    Value::Const(address) => {
+                        self.memory.insert(address, self.get(A));
+                    }
+                    _ => self.memory.clear(),
+                }
+            }
+            Add(register) | Sub(register) => {
+                let value = match (self.get(A), self.get(register)) {
+                    (Value::Const(left), Value::Const(right)) => match instruction {
+                        Add(_) => left.checked_add(right),
+                        _ => Some(left.saturating_sub(right)),
+                    },
+                    _ => None,
+                };
+                let value = value.map(Value::Const).unwrap_or_else(|| self.computed());
+                self.set(A, value);
+            }
+            Rst(register) => self.set(register, Value::Const(0)),
+            Inc(register) => self.apply(register, |value| value.checked_add(1)),
+            Dec(register) => self.apply(register, |value| Some(value.saturating_sub(1))),
+            Shl(register) => self.apply(register, |value| value.checked_mul(2)),
+            Shr(register) => self.apply(register, |value| Some(value / 2)),
+            Strk(register) => {
+                let value = self.computed();
+                self.set(register, value);
+            }
+            Label(_) | Jump(_) | Jumpr(_) | Halt => self.forget(),
+            Write | Jpos(_) | Jzero(_) => {}
+        }
+        false
+    }
+}
+
+/// Length and value of the constant built by RST, INC and SHL of one register at the
+/// start of `code`, if any.
+fn constant_build(code: &[Instruction]) -> Option<(Registers, usize, u64)> {
+    let Some(Rst(register)) = code.first() else {
+        return None;
+    };
+    let mut value: u64 = 0;
+    let mut length = 1;
+    for instruction in &code[1..] {
+        value = match instruction {
+            Inc(target) if target == register => value.checked_add(1)?,
+            Shl(target) if target == register => value.checked_mul(2)?,
+            _ => break,
+        };
+        length += 1;
+    }
+    Some((*register, length, value))
+}
+
+/// Removes what does nothing from a straight run of instructions.
+fn simplify(code: Vec<Instruction>) -> Vec<Instruction> {
+    let mut simulation = Simulation::new();
+    let mut kept = Vec::with_capacity(code.len());
+    let mut position = 0;
+    while position < code.len() {
+        if let Some((register, length, value)) = constant_build(&code[position..]) {
+            if simulation.get(register) == Value::Const(value) {
+                position += length;
+                continue;
+            }
+            let holder = [B, C, D, E, F, G, H].into_iter().find(|holder| simulation.get(*holder) == Value::Const(value));
+            if let (A, Some(holder), true) = (register, holder, length > 1) {
+                kept.push(Get(holder));
+                simulation.set(A, Value::Const(value));
+                position += length;
+                continue;
+            }
+        }
+        let instruction = code[position];
+        if !simulation.redundant(&instruction) {
+            kept.push(instruction);
+        }
+        position += 1;
+    }
+    kept
+}
+
+/// Removes jumps to the instruction right after them and code no jump reaches. The JUMP
+/// of a call is kept, since the callee returns right after it.
+fn remove_jumps(code: Vec<Instruction>) -> Vec<Instruction> {
+    let mut kept: Vec<Instruction> = Vec::with_capacity(code.len());
+    let mut reachable = true;
+    for (position, instruction) in code.iter().enumerate() {
+        if let Label(_) = instruction {
+            reachable = true;
+        }
+        if !reachable {
+            continue;
+        }
+        let call = position > 0 && matches!(code[position - 1], Strk(_));
+        if let (Jump(target) | Jpos(target) | Jzero(target), false) = (instruction, call) {
+            let mut next_labels = code[position + 1..].iter().map_while(|next| match next {
+                Label(label) => Some(*label),
+                _ => None,
+            });
+            if next_labels.any(|label| label == *target) {
+                continue;
+            }
+        }
+        kept.push(*instruction);
+        if matches!(instruction, Jump(_) | Jumpr(_) | Halt) && !call {
+            reachable = false;
+        }
+    }
+    kept
+}
+
+fn size(code: &[Instruction]) -> usize {
+    code.iter().filter(|instruction| !matches!(instruction, Label(_))).count()
+}
+
+/// Optimises the instruction stream until nothing changes, returning it with the number of
+/// instructions removed.
+pub fn optimize(mut code: Vec<Instruction>) -> (Vec<Instruction>, usize) {
+    let original = size(&code);
+    loop {
+        let before = size(&code);
+        code = simplify(remove_jumps(code));
+        if size(&code) == before {
+            break;
+        }
+    }
+    let removed = original - size(&code);
+    (code, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy_back_to_the_same_register_is_removed() {
+        let (code, removed) = optimize(vec![Read, Put(B), Get(B), Write, Halt]);
+        assert_eq!(code, vec![Read, Put(B), Write, Halt]);
+        assert_eq!(removed, 1);
+    }
+
+    #[test]
+    fn constant_already_in_a_register_is_not_rebuilt() {
+        let (code, removed) = optimize(vec![Rst(B), Inc(B), Shl(B), Rst(A), Inc(A), Shl(A), Write, Rst(B), Inc(B), Shl(B), Halt]);
+        assert_eq!(code, vec![Rst(B), Inc(B), Shl(B), Get(B), Write, Halt]);
+        assert_eq!(removed, 5);
+    }
+
+    #[test]
+    fn load_of_the_value_in_a_is_removed() {
+        let (code, removed) = optimize(vec![Rst(B), Read, Store(B), Load(B), Write, Halt]);
+        assert_eq!(code, vec![Rst(B), Read, Store(B), Write, Halt]);
+        assert_eq!(removed, 1);
+    }
+
+    #[test]
+    fn jump_to_next_and_unreachable_code_are_removed() {
+        let (code, removed) = optimize(vec![Read, Jzero(0), Label(0), Jump(1), Write, Label(1), Halt]);
+        assert_eq!(code, vec![Read, Label(0), Label(1), Halt]);
+        assert_eq!(removed, 3);
+    }
+
+    #[test]
+    fn jump_of_a_call_is_kept() {
+        let code = vec![Strk(H), Jump(0), Label(0), Write, Halt];
+        assert_eq!(optimize(code.clone()), (code, 0));
+    }
+}
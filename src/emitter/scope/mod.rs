@@ -0,0 +1,33 @@
+use std::collections::HashMap;
+
+use super::VariableVariant;
+
+/// Storage of every name declared in one activation: the main program or a single
+/// procedure call, with parameters bound to the caller's variables.
+pub(super) type Bindings = HashMap<String, VariableVariant>;
+
+/// Activations being compiled, innermost last. A procedure body sees nothing but its own
+/// parameters and locals, so names are looked up in the innermost activation only and
+/// every call gets bindings independent of other calls of the same procedure.
+#[derive(Debug, Default)]
+pub(super) struct Environment {
+    scopes: Vec<Bindings>,
+}
+
+impl Environment {
+    pub(super) fn new(main: Bindings) -> Self {
+        Environment { scopes: vec![main] }
+    }
+
+    pub(super) fn get(&self, name: &str) -> Option<&VariableVariant> {
+        self.scopes.last().and_then(|scope| scope.get(name))
+    }
+
+    pub(super) fn push(&mut self, bindings: Bindings) {
+        self.scopes.push(bindings);
+    }
+
+    pub(super) fn pop(&mut self) {
+        self.scopes.pop();
+    }
+}
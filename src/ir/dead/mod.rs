@@ -0,0 +1,116 @@
+//! Dead store elimination.
+//!
+//! A store to a cell that is not live after it is removed, and so are the loads and
+//! arithmetic computing temporaries no longer used. Removing them may leave earlier stores
+//! dead in turn, so this repeats until nothing changes. Input is always read, even when
+//! the number read is dropped.
+
+use std::collections::HashSet;
+
+use super::liveness::live_cells;
+use super::{Address, Instr, Program, Temp};
+
+/// Removes stores whose value is never read, returning whether any was.
+fn remove_dead_stores(program: &mut Program) -> bool {
+    let live = live_cells(program);
+    let before = program.code.len();
+    let code = std::mem::take(&mut program.code);
+    program.code = code.into_iter()
+        .enumerate()
+        .filter(|(index, instruction)| match instruction {
+            // A store never ends the program, so another instruction follows it.
+            Instr::Store(Address::Direct(cell), _) => live[index + 1].contains(*cell, program.in_array(*cell)),
+            _ => true,
+        })
+        .map(|(_, instruction)| instruction)
+        .collect();
+    program.code.len() != before
+}
+
+/// Removes loads and arithmetic whose result is never used, returning whether any was.
+fn remove_unused_temps(program: &mut Program) -> bool {
+    let used: HashSet<Temp> = program.code.iter().flat_map(Instr::uses).collect();
+    let before = program.code.len();
+    program.code.retain(|instruction| match instruction {
+        Instr::Load(temp, _) | Instr::Binary(temp, ..) => used.contains(temp),
+        _ => true,
+    });
+    program.code.len() != before
+}
+
+/// Removes dead stores and the computations only they used from the whole program.
+pub fn eliminate_dead_stores(program: &mut Program) {
+    while remove_dead_stores(program) | remove_unused_temps(program) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::Literal;
+    use crate::ir::{BinaryOp, Operand};
+
+    /// Cells of the only array.
+    const ARRAY: std::ops::Range<u64> = 0..4;
+
+    fn constant(value: u64) -> Operand {
+        Operand::Const(Literal::from(value))
+    }
+
+    fn eliminated(code: Vec<Instr>) -> Vec<Instr> {
+        let mut program = Program { code, temps: 8, labels: 8, arrays: vec![ARRAY] };
+        eliminate_dead_stores(&mut program);
+        program.code
+    }
+
+    #[test]
+    fn overwritten_store_is_removed() {
+        let code = eliminated(vec![
+            Instr::Store(Address::Direct(8), constant(1)),
+            Instr::Store(Address::Direct(8), constant(2)),
+            Instr::Load(0, Address::Direct(8)),
+            Instr::Write(Operand::Temp(0)),
+            Instr::Halt,
+        ]);
+        assert_eq!(code[0], Instr::Store(Address::Direct(8), constant(2)));
+        assert_eq!(code.len(), 4);
+    }
+
+    #[test]
+    fn indirect_load_keeps_only_array_stores() {
+        let code = eliminated(vec![
+            Instr::Store(Address::Direct(1), constant(5)),
+            Instr::Store(Address::Direct(8), constant(6)),
+            Instr::Read(1),
+            Instr::Load(0, Address::Indirect(1)),
+            Instr::Write(Operand::Temp(0)),
+            Instr::Halt,
+        ]);
+        assert_eq!(code[0], Instr::Store(Address::Direct(1), constant(5)));
+        assert_eq!(code[1], Instr::Read(1));
+    }
+
+    #[test]
+    fn call_keeps_stores_before_it() {
+        let code = vec![
+            Instr::Store(Address::Direct(8), constant(5)),
+            Instr::Call(0),
+            Instr::Halt,
+            Instr::Label(0),
+            Instr::Enter(9),
+            Instr::Return(9),
+        ];
+        assert_eq!(eliminated(code.clone()), code);
+    }
+
+    #[test]
+    fn computation_of_dead_store_is_removed_but_input_is_read() {
+        let code = eliminated(vec![
+            Instr::Read(0),
+            Instr::Load(1, Address::Direct(8)),
+            Instr::Binary(2, BinaryOp::Add, Operand::Temp(0), Operand::Temp(1)),
+            Instr::Store(Address::Direct(9), Operand::Temp(2)),
+            Instr::Halt,
+        ]);
+        assert_eq!(code, vec![Instr::Read(0), Instr::Halt]);
+    }
+}
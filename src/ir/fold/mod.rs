@@ -0,0 +1,392 @@
+//! Constant folding and propagation.
+//!
+//! A forward data-flow analysis over basic blocks finds the memory cells holding a value
+//! known at compile time. Loads of such cells and arithmetic on constants are replaced with
+//! their values, computed with the semantics of the language: subtraction saturates at zero,
+//! division and modulo by zero give zero. Branches on constants become jumps or disappear,
+//! and blocks no longer reached are dropped. Wide constants used several times are then
+//! kept in memory instead of being built at every use.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::ast::Literal;
+use crate::emitter::inline::CYCLES_PER_INSTRUCTION;
+use crate::vm::{MEMORY_COST, OTHER_COST};
+use super::{Address, BinaryOp, Instr, Label, Operand, Program, Relation, Temp};
+
+/// Values known to be in memory cells.
+type Cells = HashMap<u64, Literal>;
+
+/// Cycles spent building a constant in a register: a SHL per bit and an INC per one.
+fn build_cost(value: &Literal) -> u64 {
+    OTHER_COST + (0..value.bits()).map(|bit| if value.bit(bit) { 2 * OTHER_COST } else { OTHER_COST }).sum::<u64>()
+}
+
+fn evaluate(operation: BinaryOp, left: &Literal, right: &Literal) -> Literal {
+    match operation {
+        BinaryOp::Add => left.add(right),
+        BinaryOp::Sub => left.saturating_sub(right),
+        BinaryOp::Mul => left.mul(right),
+        BinaryOp::Div => left.div_rem(right).0,
+        BinaryOp::Mod => left.div_rem(right).1,
+    }
+}
+
+fn holds(relation: Relation, left: &Literal, right: &Literal) -> bool {
+    match relation {
+        Relation::Equal => left == right,
+        Relation::NotEqual => left != right,
+        Relation::Greater => left > right,
+        Relation::Lower => left < right,
+        Relation::GreaterOrEqual => left >= right,
+        Relation::LowerOrEqual => left <= right,
+    }
+}
+
+/// State while folding one block.
+struct Folder {
+    cells: Cells,
+    temps: HashMap<Temp, Literal>,
+    /// Temporaries with a known value that are still loaded, as building it costs more.
+    loaded: HashSet<Temp>,
+}
+
+impl Folder {
+    fn value(&self, operand: &Operand) -> Option<Literal> {
+        match operand {
+            Operand::Temp(temp) => self.temps.get(temp).cloned(),
+            Operand::Const(value) => Some(value.clone()),
+        }
+    }
+
+    fn operand(&self, operand: &Operand) -> Operand {
+        match operand {
+            Operand::Temp(temp) if !self.loaded.contains(temp) => match self.temps.get(temp) {
+                Some(value) => Operand::Const(value.clone()),
+                None => operand.clone(),
+            },
+            _ => operand.clone(),
+        }
+    }
+
+    fn address(&self, address: Address) -> Address {
+        match address {
+            Address::Indirect(temp) => match self.temps.get(&temp).and_then(Literal::to_u64) {
+                Some(cell) => Address::Direct(cell),
+                None => address,
+            },
+            Address::Direct(_) => address,
+        }
+    }
+
+    /// Folds one instruction, returning what replaces it.
+    fn step(&mut self, instruction: &Instr) -> Option<Instr> {
+        match instruction {
+            Instr::Load(temp, address) => {
+                let address = self.address(*address);
+                if let Address::Direct(cell) = address {
+                    if let Some(value) = self.cells.get(&cell) {
+                        self.temps.insert(*temp, value.clone());
+                        if build_cost(value) <= MEMORY_COST {
+                            return None;
+                        }
+                        self.loaded.insert(*temp);
+                    }
+                }
+                Some(Instr::Load(*temp, address))
+            }
+            Instr::Store(address, operand) => {
+                let (address, operand) = (self.address(*address), self.operand(operand));
+                match (address, &operand) {
+                    (Address::Direct(cell), Operand::Const(value)) => {
+                        self.cells.insert(cell, value.clone());
+                    }
+                    (Address::Direct(cell), Operand::Temp(_)) => {
+                        self.cells.remove(&cell);
+                    }
+                    // Any cell may be the one written.
+                    (Address::Indirect(_), _) => self.cells.clear(),
+                }
+                Some(Instr::Store(address, operand))
+            }
+            Instr::Binary(temp, operation, left, right) => match (self.value(left), self.value(right)) {
+                (Some(left), Some(right)) => {
+                    self.temps.insert(*temp, evaluate(*operation, &left, &right));
+                    None
+                }
+                _ => Some(Instr::Binary(*temp, *operation, self.operand(left), self.operand(right))),
+            },
+            Instr::Write(operand) => Some(Instr::Write(self.operand(operand))),
+            Instr::Branch(relation, left, right, label) => match (self.value(left), self.value(right)) {
+                (Some(left), Some(right)) => holds(*relation, &left, &right).then_some(Instr::Jump(*label)),
+                _ => Some(Instr::Branch(*relation, self.operand(left), self.operand(right), *label)),
+            },
+            // The callee may write any cell.
+            Instr::Call(_) => {
+                self.cells.clear();
+                Some(instruction.clone())
+            }
+            Instr::Enter(return_slot) => {
+                self.cells.remove(return_slot);
+                Some(instruction.clone())
+            }
+            _ => Some(instruction.clone()),
+        }
+    }
+}
+
+/// Folds a block entered with the given cells, returning its new code and the cells known
+/// at its end.
+fn fold_block(code: &[Instr], cells: Cells) -> (Vec<Instr>, Cells) {
+    let mut folder = Folder { cells, temps: HashMap::new(), loaded: HashSet::new() };
+    let code = code.iter().filter_map(|instruction| folder.step(instruction)).collect();
+    (code, folder.cells)
+}
+
+/// Cells with the same value in both states.
+fn meet(left: &Cells, right: &Cells) -> Cells {
+    left.iter()
+        .filter(|(cell, value)| right.get(cell) == Some(value))
+        .map(|(cell, value)| (*cell, value.clone()))
+        .collect()
+}
+
+/// Folds constants in the whole program.
+pub fn fold_constants(program: &mut Program) {
+    let blocks = program.blocks();
+    if blocks.is_empty() {
+        return;
+    }
+    let block_of_label: HashMap<Label, usize> = blocks.iter()
+        .enumerate()
+        .filter_map(|(index, block)| match program.code[block.start] {
+            Instr::Label(label) => Some((label, index)),
+            _ => None,
+        })
+        .collect();
+    // Blocks reached from the start of the program or through calls; procedures start
+    // knowing nothing about memory.
+    let successors = |block: usize, code: &[Instr], cells: Cells| -> Vec<(usize, Cells)> {
+        let mut successors: Vec<(usize, Cells)> = code.iter()
+            .filter_map(|instruction| match instruction {
+                Instr::Call(label) => Some((block_of_label[label], Cells::new())),
+                _ => None,
+            })
+            .collect();
+        match code.last() {
+            Some(Instr::Jump(label)) => successors.push((block_of_label[label], cells)),
+            Some(Instr::Return(_) | Instr::Halt) => {}
+            last => {
+                if let Some(Instr::Branch(_, _, _, label)) = last {
+                    successors.push((block_of_label[label], cells.clone()));
+                }
+                if block + 1 < blocks.len() {
+                    successors.push((block + 1, cells));
+                }
+            }
+        }
+        successors
+    };
+
+    let mut entries: Vec<Option<Cells>> = vec![None; blocks.len()];
+    entries[0] = Some(Cells::new());
+    let mut worklist = vec![0];
+    while let Some(block) = worklist.pop() {
+        let entry = entries[block].clone().unwrap_or_default();
+        let (code, cells) = fold_block(&program.code[blocks[block].clone()], entry);
+        for (successor, cells) in successors(block, &code, cells) {
+            let merged = match &entries[successor] {
+                Some(known) => meet(known, &cells),
+                None => cells,
+            };
+            if entries[successor].as_ref() != Some(&merged) {
+                entries[successor] = Some(merged);
+                worklist.push(successor);
+            }
+        }
+    }
+
+    let mut code = Vec::new();
+    for (block, entry) in blocks.iter().zip(entries) {
+        if let Some(entry) = entry {
+            code.extend(fold_block(&program.code[block.clone()], entry).0);
+        }
+    }
+    program.code = remove_redundant_jumps(code);
+}
+
+/// Operands of an instruction that may be constants.
+fn operands_mut(instruction: &mut Instr) -> Vec<&mut Operand> {
+    match instruction {
+        Instr::Store(_, operand) | Instr::Write(operand) => vec![operand],
+        Instr::Binary(_, _, left, right) | Instr::Branch(_, left, right, _) => vec![left, right],
+        _ => vec![],
+    }
+}
+
+/// Keeps constants that cost more to build than a LOAD, and are used several times, in
+/// cells from `first_cell` on: each is built and stored once at the start of the program,
+/// then loaded at every use, when that costs less in cycles and code size than building it
+/// every time. Returns the number of cells taken.
+pub fn pool_constants(program: &mut Program, first_cell: u64) -> u64 {
+    let mut uses: HashMap<Literal, u64> = HashMap::new();
+    for instruction in &mut program.code {
+        for operand in operands_mut(instruction) {
+            if let Operand::Const(value) = operand {
+                *uses.entry(value.clone()).or_default() += 1;
+            }
+        }
+    }
+    // Every instruction takes a cycle, so building costs its size in cycles as well.
+    let build = |value: &Literal| build_cost(value) * (1 + CYCLES_PER_INSTRUCTION);
+    let load = MEMORY_COST + CYCLES_PER_INSTRUCTION;
+    let mut values: Vec<Literal> = uses.into_iter()
+        .filter(|(value, count)| build_cost(value) > MEMORY_COST && build(value) + load * (count + 1) < build(value) * count)
+        .map(|(value, _)| value)
+        .collect();
+    values.sort_unstable();
+    let pooled: Vec<(Literal, u64)> = values.into_iter().zip(first_cell..).collect();
+    if pooled.is_empty() {
+        return 0;
+    }
+
+    let mut code: Vec<Instr> = pooled.iter()
+        .map(|(value, cell)| Instr::Store(Address::Direct(*cell), Operand::Const(value.clone())))
+        .collect();
+    for mut instruction in std::mem::take(&mut program.code) {
+        let mut loads = Vec::new();
+        for operand in operands_mut(&mut instruction) {
+            let Operand::Const(value) = operand else { continue };
+            if let Some((_, cell)) = pooled.iter().find(|(pooled, _)| pooled == value) {
+                let temp = program.new_temp();
+                loads.push(Instr::Load(temp, Address::Direct(*cell)));
+                *operand = Operand::Temp(temp);
+            }
+        }
+        code.extend(loads);
+        code.push(instruction);
+    }
+    program.code = code;
+    pooled.len() as u64
+}
+
+/// Drops jumps and branches to the instruction right after them, then labels nothing jumps to.
+fn remove_redundant_jumps(code: Vec<Instr>) -> Vec<Instr> {
+    let mut kept: Vec<Instr> = Vec::with_capacity(code.len());
+    for (index, instruction) in code.iter().enumerate() {
+        if let Instr::Jump(label) | Instr::Branch(_, _, _, label) = instruction {
+            let next_labels = code[index + 1..].iter().map_while(|next| match next {
+                Instr::Label(next) => Some(*next),
+                _ => None,
+            });
+            if next_labels.into_iter().any(|next| next == *label) {
+                continue;
+            }
+        }
+        kept.push(instruction.clone());
+    }
+    let targets: Vec<Label> = kept.iter().filter_map(Instr::target).collect();
+    kept.retain(|instruction| !matches!(instruction, Instr::Label(label) if !targets.contains(label)));
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn constant(value: u64) -> Operand {
+        Operand::Const(Literal::from(value))
+    }
+
+    fn folded(code: Vec<Instr>) -> Vec<Instr> {
+        let mut program = Program { code, temps: 8, labels: 8, arrays: Vec::new() };
+        fold_constants(&mut program);
+        program.code
+    }
+
+    #[test]
+    fn branch_on_constants_keeps_only_the_taken_side() {
+        let code = folded(vec![
+            Instr::Store(Address::Direct(0), constant(5)),
+            Instr::Load(0, Address::Direct(0)),
+            Instr::Branch(Relation::Greater, Operand::Temp(0), constant(3), 0),
+            Instr::Write(constant(1)),
+            Instr::Jump(1),
+            Instr::Label(0),
+            Instr::Write(constant(2)),
+            Instr::Label(1),
+            Instr::Halt,
+        ]);
+        assert_eq!(code, vec![Instr::Store(Address::Direct(0), constant(5)), Instr::Write(constant(2)), Instr::Halt]);
+    }
+
+    #[test]
+    fn subtraction_saturates_at_zero() {
+        let code = folded(vec![
+            Instr::Binary(0, BinaryOp::Sub, constant(3), constant(5)),
+            Instr::Write(Operand::Temp(0)),
+            Instr::Halt,
+        ]);
+        assert_eq!(code, vec![Instr::Write(constant(0)), Instr::Halt]);
+    }
+
+    #[test]
+    fn division_and_modulo_by_zero_give_zero() {
+        let code = folded(vec![
+            Instr::Binary(0, BinaryOp::Div, constant(7), constant(0)),
+            Instr::Write(Operand::Temp(0)),
+            Instr::Binary(1, BinaryOp::Mod, constant(7), constant(0)),
+            Instr::Write(Operand::Temp(1)),
+            Instr::Halt,
+        ]);
+        assert_eq!(code, vec![Instr::Write(constant(0)), Instr::Write(constant(0)), Instr::Halt]);
+    }
+
+    #[test]
+    fn cells_written_by_a_call_are_not_known_after_it() {
+        let code = vec![
+            Instr::Store(Address::Direct(0), constant(5)),
+            Instr::Call(0),
+            Instr::Load(0, Address::Direct(0)),
+            Instr::Write(Operand::Temp(0)),
+            Instr::Halt,
+            Instr::Label(0),
+            Instr::Enter(1),
+            Instr::Store(Address::Direct(0), constant(6)),
+            Instr::Return(1),
+        ];
+        assert_eq!(folded(code.clone()), code);
+    }
+
+    #[test]
+    fn wide_constant_used_twice_is_kept_in_memory() {
+        let wide = constant((1 << 40) - 1);
+        let code = vec![
+            Instr::Write(wide.clone()),
+            Instr::Write(wide.clone()),
+            Instr::Write(constant(1000)),
+            Instr::Write(constant(1000)),
+            Instr::Halt,
+        ];
+        let mut program = Program { code, temps: 8, labels: 8, arrays: Vec::new() };
+        assert_eq!(pool_constants(&mut program, 20), 1);
+        assert_eq!(program.code, vec![
+            Instr::Store(Address::Direct(20), wide),
+            Instr::Load(8, Address::Direct(20)),
+            Instr::Write(Operand::Temp(8)),
+            Instr::Load(9, Address::Direct(20)),
+            Instr::Write(Operand::Temp(9)),
+            Instr::Write(constant(1000)),
+            Instr::Write(constant(1000)),
+            Instr::Halt,
+        ]);
+    }
+
+    #[test]
+    fn wide_constant_used_once_is_built_in_place() {
+        let code = vec![Instr::Write(constant((1 << 40) - 1)), Instr::Halt];
+        let mut program = Program { code: code.clone(), temps: 8, labels: 8, arrays: Vec::new() };
+        assert_eq!(pool_constants(&mut program, 20), 0);
+        assert_eq!(program.code, code);
+    }
+}
@@ -0,0 +1,110 @@
+//! Liveness of memory cells: which cells may still be read before being written again.
+//!
+//! Only cells with addresses known at compile time are tracked one by one. An indirect load
+//! may read any array cell, while a call may read any cell and the caller of a procedure
+//! may read any cell after it returns.
+
+use std::collections::BTreeSet;
+
+use super::{Address, Instr, Program};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LiveCells {
+    /// Every cell is live.
+    all: bool,
+    /// Every array cell is live.
+    arrays: bool,
+    cells: BTreeSet<u64>,
+}
+
+impl LiveCells {
+    pub fn contains(&self, cell: u64, in_array: bool) -> bool {
+        self.all || (self.arrays && in_array) || self.cells.contains(&cell)
+    }
+
+    fn union(&mut self, other: &LiveCells) {
+        self.all |= other.all;
+        self.arrays |= other.arrays;
+        self.cells.extend(other.cells.iter().copied());
+    }
+
+    /// Updates the cells live after an instruction to those live before it.
+    fn step_back(&mut self, instruction: &Instr) {
+        match instruction {
+            Instr::Load(_, Address::Direct(cell)) => {
+                self.cells.insert(*cell);
+            }
+            Instr::Load(_, Address::Indirect(_)) => self.arrays = true,
+            Instr::Store(Address::Direct(cell), _) => {
+                self.cells.remove(cell);
+            }
+            Instr::Call(_) | Instr::Return(_) => self.all = true,
+            Instr::Halt => *self = LiveCells::default(),
+            _ => {}
+        }
+    }
+}
+
+/// Cells live before every instruction of the program.
+pub fn live_cells(program: &Program) -> Vec<LiveCells> {
+    let blocks = program.blocks();
+    let block_at: std::collections::HashMap<usize, usize> = blocks.iter()
+        .enumerate()
+        .map(|(index, block)| (block.start, index))
+        .collect();
+    let label_block = |label| {
+        blocks.iter()
+            .position(|block| program.code[block.start] == Instr::Label(label))
+            .expect("jump to a missing label")
+    };
+    let successors: Vec<Vec<usize>> = blocks.iter()
+        .enumerate()
+        .map(|(index, block)| {
+            let last = &program.code[block.end - 1];
+            let mut successors = Vec::new();
+            if let Instr::Jump(label) | Instr::Branch(_, _, _, label) = last {
+                successors.push(label_block(*label));
+            }
+            if !matches!(last, Instr::Jump(_) | Instr::Return(_) | Instr::Halt) && block_at.contains_key(&block.end) {
+                successors.push(index + 1);
+            }
+            successors
+        })
+        .collect();
+
+    let block_entry = |index: usize, live_in: &[LiveCells]| {
+        let mut live = LiveCells::default();
+        for successor in &successors[index] {
+            live.union(&live_in[*successor]);
+        }
+        for instruction in program.code[blocks[index].clone()].iter().rev() {
+            live.step_back(instruction);
+        }
+        live
+    };
+    let mut live_in = vec![LiveCells::default(); blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for index in (0..blocks.len()).rev() {
+            let live = block_entry(index, &live_in);
+            if live != live_in[index] {
+                live_in[index] = live;
+                changed = true;
+            }
+        }
+    }
+
+    let mut live_before = vec![LiveCells::default(); program.code.len()];
+    for (index, block) in blocks.iter().enumerate() {
+        let mut live = LiveCells::default();
+        for successor in &successors[index] {
+            live.union(&live_in[*successor]);
+        }
+        for position in block.clone().rev() {
+            live.step_back(&program.code[position]);
+            live_before[position] = live.clone();
+        }
+    }
+    live_before
+}
@@ -0,0 +1,202 @@
+//! Three-address code between the AST and machine instructions.
+//!
+//! A program is a flat list of instructions over an unlimited supply of temporaries, each
+//! assigned exactly once. Temporaries hold values of a single command: none is used after a
+//! label or a jump, so values only flow between commands through memory. Control flow uses
+//! numbered labels instead of offsets.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::ast::Literal;
+
+pub mod dead;
+pub mod fold;
+pub mod liveness;
+
+pub type Temp = usize;
+pub type Label = usize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    Const(Literal),
+    Temp(Temp),
+}
+
+/// Memory cell read or written by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Address {
+    /// Cell with an address known at compile time.
+    Direct(u64),
+    /// Cell whose address is the value of a temporary.
+    Indirect(Temp),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relation {
+    Equal,
+    NotEqual,
+    Greater,
+    Lower,
+    GreaterOrEqual,
+    LowerOrEqual,
+}
+
+impl Relation {
+    pub fn negate(self) -> Relation {
+        match self {
+            Relation::Equal => Relation::NotEqual,
+            Relation::NotEqual => Relation::Equal,
+            Relation::Greater => Relation::LowerOrEqual,
+            Relation::Lower => Relation::GreaterOrEqual,
+            Relation::GreaterOrEqual => Relation::Lower,
+            Relation::LowerOrEqual => Relation::Greater,
+        }
+    }
+
+    /// Whether the relation is tested by subtracting the right operand from the left one;
+    /// otherwise the left one is subtracted from the right one. Equality tests both.
+    pub fn subtracts_right(self) -> bool {
+        matches!(self, Relation::Greater | Relation::LowerOrEqual | Relation::Equal | Relation::NotEqual)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Instr {
+    /// `t := memory[address]`
+    Load(Temp, Address),
+    /// `memory[address] := operand`
+    Store(Address, Operand),
+    /// `t := left op right`, with the saturating subtraction of the machine.
+    Binary(Temp, BinaryOp, Operand, Operand),
+    /// `t := ` next number of the input.
+    Read(Temp),
+    Write(Operand),
+    Label(Label),
+    Jump(Label),
+    /// Jumps to the label if the relation holds, falls through otherwise.
+    Branch(Relation, Operand, Operand, Label),
+    /// Enters a procedure compiled out of line, which starts at the label.
+    Call(Label),
+    /// Start of a procedure body: saves the return address in the given cell.
+    Enter(u64),
+    /// End of a procedure body: returns to the address saved in the given cell.
+    Return(u64),
+    Halt,
+}
+
+impl Instr {
+    /// Temporaries read by the instruction.
+    pub fn uses(&self) -> Vec<Temp> {
+        let operands: Vec<&Operand> = match self {
+            Instr::Store(_, operand) | Instr::Write(operand) => vec![operand],
+            Instr::Binary(_, _, left, right) | Instr::Branch(_, left, right, _) => vec![left, right],
+            _ => vec![],
+        };
+        let mut temps: Vec<Temp> = operands.into_iter()
+            .filter_map(|operand| match operand {
+                Operand::Temp(temp) => Some(*temp),
+                Operand::Const(_) => None,
+            })
+            .collect();
+        if let Instr::Load(_, Address::Indirect(temp)) | Instr::Store(Address::Indirect(temp), _) = self {
+            temps.push(*temp);
+        }
+        temps
+    }
+
+    /// Label the instruction jumps to, if any.
+    pub fn target(&self) -> Option<Label> {
+        match self {
+            Instr::Jump(label) | Instr::Branch(_, _, _, label) | Instr::Call(label) => Some(*label),
+            _ => None,
+        }
+    }
+
+    /// Whether the instruction ends a basic block. Calls do not: the callee returns to the
+    /// next instruction.
+    pub fn ends_block(&self) -> bool {
+        matches!(self, Instr::Jump(_) | Instr::Branch(..) | Instr::Return(_) | Instr::Halt)
+    }
+}
+
+/// Instructions of the whole program: the main program ending with `Halt`, followed by
+/// every procedure compiled out of line.
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub code: Vec<Instr>,
+    pub temps: usize,
+    pub labels: usize,
+    /// Cells of every array, which indirect loads and stores may access. The other cells
+    /// are only accessed directly, except through references in procedures called out of line.
+    pub arrays: Vec<Range<u64>>,
+}
+
+impl Program {
+    pub fn new_temp(&mut self) -> Temp {
+        self.temps += 1;
+        self.temps - 1
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels += 1;
+        self.labels - 1
+    }
+
+    pub fn in_array(&self, cell: u64) -> bool {
+        self.arrays.iter().any(|array| array.contains(&cell))
+    }
+
+    /// First and last instruction of every loop, ordered by start: a label together with the
+    /// last jump or branch back to it.
+    pub fn loops(&self) -> Vec<(usize, usize)> {
+        let mut starts = HashMap::new();
+        let mut loops = Vec::new();
+        for (index, instruction) in self.code.iter().enumerate() {
+            match instruction {
+                Instr::Label(label) => {
+                    starts.insert(*label, index);
+                }
+                Instr::Jump(label) | Instr::Branch(_, _, _, label) => {
+                    if let Some(start) = starts.get(label) {
+                        loops.retain(|(other, _)| other != start);
+                        loops.push((*start, index));
+                    }
+                }
+                _ => {}
+            }
+        }
+        loops.sort_unstable();
+        loops
+    }
+
+    /// Splits the code into basic blocks, entered only at their first instruction and left
+    /// only after their last one. Every label starts a block.
+    pub fn blocks(&self) -> Vec<Range<usize>> {
+        let mut blocks = Vec::new();
+        let mut start = 0;
+        for (index, instruction) in self.code.iter().enumerate() {
+            if matches!(instruction, Instr::Label(_)) && index > start {
+                blocks.push(start..index);
+                start = index;
+            }
+            if instruction.ends_block() {
+                blocks.push(start..index + 1);
+                start = index + 1;
+            }
+        }
+        if start < self.code.len() {
+            blocks.push(start..self.code.len());
+        }
+        blocks
+    }
+}
@@ -1,82 +1,139 @@
 mod emitter;
 mod ast;
+mod bench;
+mod bignum;
+mod checker;
+mod diagnostics;
+mod driver;
+mod ir;
+mod vm;
 
 use lalrpop_util::lalrpop_mod;
-lalrpop_mod!(pub lexparse);
+lalrpop_mod!(#[allow(clippy::all)] pub lexparse);
 
 use std::env;
 use std::fs;
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io;
 
 use emitter::*;
-use emitter::error::CompilerError;
+use diagnostics::SourceFile;
+
+const USAGE: &str = "usage: kompilator [--inline=always|never|auto] [--routines=inline|shared|auto] [--stats] <input> <output>
+       kompilator run [--bignum] <program>
+       kompilator bench [--inline=always|never|auto] [--routines=inline|shared|auto] [--baseline=<file>] [--tolerance=<percent>] [--update] [<directory>]";
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        panic!("Please supply exactly 2 arguments.");
+    if env::args().nth(1).as_deref() == Some("run") {
+        let mut bignum = false;
+        let mut paths: Vec<String> = Vec::new();
+        for arg in env::args().skip(2) {
+            if arg == "--bignum" {
+                bignum = true;
+            } else if arg.starts_with("--") {
+                usage_error(&format!("unknown option `{}`", arg));
+            } else {
+                paths.push(arg);
+            }
+        }
+        if paths.len() != 1 {
+            usage_error("expected a program to run");
+        }
+        if bignum {
+            run_program::<bignum::Integer>(&paths[0]);
+        } else {
+            run_program::<i64>(&paths[0]);
+        }
+        return;
     }
 
-    let input_file_path = args[1].clone();
-    let output_file_path = args[2].clone();
-    let compilee = fs::read_to_string(&input_file_path)
-        .expect("Failed to read input file");
-
-    match lexparse::ProgramParser::new().parse(&compilee) {
-        Ok(ast) => {
-            if let Ok(mut pseudo_assembler) = Emitter::new(ast) {
-                if let Ok(_) = pseudo_assembler.construct() {
-                    let ass = pseudo_assembler.emit();
-                    fs::write(&output_file_path, ass)
-                        .expect("Unable to write to file");
-                } else {
-                    eprintln!("Error in constructing pseudo assembler");
-                    std::process::exit(1);
-                }
+    if env::args().nth(1).as_deref() == Some("bench") {
+        let mut options = bench::BenchOptions::default();
+        let mut directories: Vec<String> = Vec::new();
+        for arg in env::args().skip(2) {
+            if let Some(mode) = arg.strip_prefix("--inline=") {
+                options.compiler.inline = mode.parse().unwrap_or_else(|message: String| usage_error(&message));
+            } else if let Some(mode) = arg.strip_prefix("--routines=") {
+                options.compiler.routines = mode.parse().unwrap_or_else(|message: String| usage_error(&message));
+            } else if let Some(path) = arg.strip_prefix("--baseline=") {
+                options.baseline = path.to_string();
+            } else if let Some(percent) = arg.strip_prefix("--tolerance=") {
+                options.tolerance = percent.parse()
+                    .unwrap_or_else(|_| usage_error(&format!("invalid tolerance `{}`", percent)));
+            } else if arg == "--update" {
+                options.update = true;
+            } else if arg.starts_with("--") {
+                usage_error(&format!("unknown option `{}`", arg));
             } else {
-                eprintln!("Error in Emitter initialization");
-                std::process::exit(1);
+                directories.push(arg);
             }
-        },
-        Err(_) => println!("Syntax Error"),
-    };
-}
-#[warn(dead_code)]
-fn write_message_and_exit(error: CompilerError, input_file_path: &str) {
-    let line_no = find_line_number(input_file_path, error.get_byte())
-        .unwrap_or_else(|| {
-            eprintln!("Unable to find line number");
+        }
+        match directories.len() {
+            0 => {}
+            1 => options.directory = directories.remove(0),
+            _ => usage_error("expected at most one directory of examples"),
+        }
+        if !bench::run(&options) {
             std::process::exit(1);
-        });
+        }
+        return;
+    }
 
-    let error_message = match error {
-        CompilerError::UndeclaredVariable(id, _) |
-        CompilerError::IncorrectUseOfVariable(id, _) |
-        CompilerError::IndexOutOfBounds(id, _) |
-        CompilerError::ArrayUsedAsIndex(id, _) |
-        CompilerError::WrongArgumentType(id, _) |
-        CompilerError::DuplicateVariableDeclaration(id, _) => {
-            format!("ERROR: `{}` line: {}", id.split('@').next().unwrap(), line_no)
-        },
-        _ => format!("ERROR: {:?} line: {}", error, line_no),
-    };
+    let mut options = Options::default();
+    let mut stats = false;
+    let mut paths: Vec<String> = Vec::new();
+    for arg in env::args().skip(1) {
+        if let Some(mode) = arg.strip_prefix("--inline=") {
+            options.inline = mode.parse().unwrap_or_else(|message: String| usage_error(&message));
+        } else if let Some(mode) = arg.strip_prefix("--routines=") {
+            options.routines = mode.parse().unwrap_or_else(|message: String| usage_error(&message));
+        } else if arg == "--stats" {
+            stats = true;
+        } else if arg.starts_with("--") {
+            usage_error(&format!("unknown option `{}`", arg));
+        } else {
+            paths.push(arg);
+        }
+    }
+    if paths.len() != 2 {
+        usage_error("expected an input and an output file");
+    }
 
-    println!("{}", error_message);
-    std::process::exit(1);
+    let compilee = fs::read_to_string(&paths[0])
+        .expect("Failed to read input file");
+    let source = SourceFile::new(&paths[0], &compilee);
+    let compilation = driver::compile(&source, options);
+    for message in compilation.warnings.iter().chain(&compilation.errors) {
+        eprint!("{}", message);
+    }
+    let Some(ass) = compilation.assembly else {
+        std::process::exit(1);
+    };
+    if stats {
+        eprintln!("memory footprint: {} cells", compilation.memory_footprint);
+        eprintln!("peephole: {} instructions removed", compilation.peephole_savings);
+    }
+    fs::write(&paths[1], ass)
+        .expect("Unable to write to file");
 }
 
-fn find_line_number(file_path: &str, byte_index: usize) -> Option<usize> {
-    let file = File::open(file_path).unwrap();
-    let reader = BufReader::new(file);
-
-    let mut total_bytes = 0;
-    for (i, line) in reader.lines().enumerate() {
-        let line = line.unwrap();
-        total_bytes += line.len() + 1; // +1 for the '\n' character
-        if total_bytes >= byte_index {
-            return Some(i + 1); // +1 because line numbers start from 1
+/// Executes a compiled program on the built-in virtual machine, reading its input from stdin.
+/// `i64` words behave like `mw.cc`, `bignum::Integer` words like `mw-cln.cc`.
+fn run_program<V: vm::Word>(path: &str) {
+    let text = fs::read_to_string(path).unwrap_or_else(|error| {
+        eprintln!("error: cannot read {}: {}", path, error);
+        std::process::exit(1);
+    });
+    let result = vm::parse(&text).and_then(|program| vm::run::<V, _, _>(&program, io::stdin().lock(), io::stdout()));
+    match result {
+        Ok(cost) => println!("program finished (cost: {}; i/o: {})", cost.total, cost.io),
+        Err(error) => {
+            eprintln!("error: {}", error);
+            std::process::exit(1);
         }
     }
-    None
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("error: {}\n{}", message, USAGE);
+    std::process::exit(2);
 }
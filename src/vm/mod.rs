@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::bignum::Integer;
+
+/// Instruction of the target machine as read from the emitted text. Register operands are
+/// indices 0–7 standing for registers a–h; jump operands are absolute instruction numbers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Read,
+    Write,
+    Load(usize),
+    Store(usize),
+    Add(usize),
+    Sub(usize),
+    Get(usize),
+    Put(usize),
+    Rst(usize),
+    Inc(usize),
+    Dec(usize),
+    Shl(usize),
+    Shr(usize),
+    Jump(usize),
+    Jpos(usize),
+    Jzero(usize),
+    Strk(usize),
+    Jumpr(usize),
+    Halt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    /// Malformed program text, with the line it was found on.
+    Syntax(usize, String),
+    /// Jump to an instruction outside of the program.
+    InvalidInstruction(String),
+    InvalidInput(String),
+    EndOfInput,
+    Io(String),
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmError::Syntax(line, message) => write!(f, "line {}: {}", line, message),
+            VmError::InvalidInstruction(lr) => write!(f, "call of nonexistent instruction {}", lr),
+            VmError::InvalidInput(input) => write!(f, "invalid input `{}`", input),
+            VmError::EndOfInput => write!(f, "unexpected end of input"),
+            VmError::Io(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<std::io::Error> for VmError {
+    fn from(error: std::io::Error) -> Self {
+        VmError::Io(error.to_string())
+    }
+}
+
+/// Value of a register or a memory cell. `i64` behaves like `mw.cc`, `Integer` like the
+/// arbitrary-precision `mw-cln.cc`.
+pub trait Word: Clone + Eq + Hash + Ord + Display + FromStr {
+    fn from_i64(value: i64) -> Self;
+    /// Instruction number held by the word, if it can be one.
+    fn to_address(&self) -> Option<i64>;
+    fn add(&self, other: &Self) -> Self;
+    /// SUB as defined by the machine: subtracts `other` unless it is larger, giving zero then.
+    fn sub_or_zero(&self, other: &Self) -> Self;
+    fn shl(&self) -> Self;
+    fn shr(&self) -> Self;
+
+    fn is_zero(&self) -> bool {
+        *self == Self::from_i64(0)
+    }
+
+    fn is_positive(&self) -> bool {
+        *self > Self::from_i64(0)
+    }
+}
+
+impl Word for i64 {
+    fn from_i64(value: i64) -> Self {
+        value
+    }
+
+    fn to_address(&self) -> Option<i64> {
+        Some(*self)
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        self.wrapping_add(*other)
+    }
+
+    fn sub_or_zero(&self, other: &Self) -> Self {
+        if self >= other { self.wrapping_sub(*other) } else { 0 }
+    }
+
+    fn shl(&self) -> Self {
+        self << 1
+    }
+
+    fn shr(&self) -> Self {
+        self >> 1
+    }
+}
+
+impl Word for Integer {
+    fn from_i64(value: i64) -> Self {
+        Integer::from(value)
+    }
+
+    fn to_address(&self) -> Option<i64> {
+        self.to_i64()
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        Integer::add(self, other)
+    }
+
+    fn sub_or_zero(&self, other: &Self) -> Self {
+        if self >= other { self.sub(other) } else { Integer::default() }
+    }
+
+    fn shl(&self) -> Self {
+        Integer::shl(self)
+    }
+
+    fn shr(&self) -> Self {
+        Integer::shr(self)
+    }
+}
+
+/// Cycles of LOAD and STORE.
+pub const MEMORY_COST: u64 = 50;
+/// Cycles of ADD and SUB.
+pub const ARITHMETIC_COST: u64 = 5;
+/// Cycles of READ and WRITE.
+pub const IO_COST: u64 = 100;
+/// Cycles of any other instruction.
+pub const OTHER_COST: u64 = 1;
+
+/// Cycles spent by a finished program, counted like `mw.cc` with the costs above.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Cost {
+    /// All cycles, input and output included.
+    pub total: u64,
+    pub io: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Command(&'static str),
+    Register(usize),
+    Number(usize),
+}
+
+const COMMANDS: [&str; 19] = [
+    "READ", "WRITE", "LOAD", "STORE", "ADD", "SUB", "GET", "PUT", "RST", "INC", "DEC", "SHL", "SHR",
+    "JUMP", "JPOS", "JZERO", "STRK", "JUMPR", "HALT",
+];
+
+/// Splits program text into tokens, each with its line number. Comments run from `#` to
+/// the end of the line.
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, VmError> {
+    let mut tokens = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line_number = number + 1;
+        let code = line.split('#').next().unwrap_or("");
+        let mut chars = code.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            let mut end = start + c.len_utf8();
+            let mut extend = |predicate: fn(char) -> bool| {
+                while let Some((i, c)) = chars.peek().copied() {
+                    if !predicate(c) {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                end
+            };
+            let token = match c {
+                ' ' | '\t' | '\r' => continue,
+                'A'..='Z' => {
+                    let end = extend(|c| c.is_ascii_uppercase());
+                    let word = &code[start..end];
+                    match COMMANDS.iter().find(|command| **command == word) {
+                        Some(command) => Token::Command(command),
+                        None => return Err(VmError::Syntax(line_number, format!("unrecognised symbol `{}`", word))),
+                    }
+                }
+                'a'..='h' => Token::Register(c as usize - 'a' as usize),
+                '0'..='9' => {
+                    let end = extend(|c| c.is_ascii_digit());
+                    let number = code[start..end].parse()
+                        .map_err(|_| VmError::Syntax(line_number, format!("number `{}` too large", &code[start..end])))?;
+                    Token::Number(number)
+                }
+                _ => return Err(VmError::Syntax(line_number, format!("unrecognised symbol `{}`", c))),
+            };
+            tokens.push((token, line_number));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Reads a program in the text format accepted by the virtual machine.
+pub fn parse(text: &str) -> Result<Vec<Instruction>, VmError> {
+    let tokens = tokenize(text)?;
+    let mut program = Vec::new();
+    let mut tokens = tokens.into_iter();
+    while let Some((token, line)) = tokens.next() {
+        let Token::Command(command) = token else {
+            return Err(VmError::Syntax(line, "expected an instruction".to_string()));
+        };
+        let mut operand = || match tokens.next() {
+            Some((Token::Register(register), _)) => Ok((register, true)),
+            Some((Token::Number(number), _)) => Ok((number, false)),
+            _ => Err(VmError::Syntax(line, format!("missing operand of {}", command))),
+        };
+        let instruction = match command {
+            "READ" => Instruction::Read,
+            "WRITE" => Instruction::Write,
+            "HALT" => Instruction::Halt,
+            // Like in `mw.cc`, a register in a jump stands for its index and a number in
+            // JUMPR for a register.
+            "JUMP" => Instruction::Jump(operand()?.0),
+            "JPOS" => Instruction::Jpos(operand()?.0),
+            "JZERO" => Instruction::Jzero(operand()?.0),
+            "JUMPR" => match operand()? {
+                (register, _) if register < 8 => Instruction::Jumpr(register),
+                (number, _) => return Err(VmError::Syntax(line, format!("no register {}", number))),
+            },
+            _ => {
+                let (register, true) = operand()? else {
+                    return Err(VmError::Syntax(line, format!("{} expects a register", command)));
+                };
+                match command {
+                    "LOAD" => Instruction::Load(register),
+                    "STORE" => Instruction::Store(register),
+                    "ADD" => Instruction::Add(register),
+                    "SUB" => Instruction::Sub(register),
+                    "GET" => Instruction::Get(register),
+                    "PUT" => Instruction::Put(register),
+                    "RST" => Instruction::Rst(register),
+                    "INC" => Instruction::Inc(register),
+                    "DEC" => Instruction::Dec(register),
+                    "SHL" => Instruction::Shl(register),
+                    "SHR" => Instruction::Shr(register),
+                    _ => Instruction::Strk(register),
+                }
+            }
+        };
+        program.push(instruction);
+    }
+    Ok(program)
+}
+
+/// Reads whitespace separated numbers, a line at a time so that an interactive user is
+/// asked for each of them.
+struct NumberReader<R: BufRead> {
+    input: R,
+    pending: Vec<String>,
+}
+
+impl<R: BufRead> NumberReader<R> {
+    fn next<W: Word>(&mut self) -> Result<W, VmError> {
+        while self.pending.is_empty() {
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                return Err(VmError::EndOfInput);
+            }
+            self.pending = line.split_whitespace().rev().map(str::to_string).collect();
+        }
+        let word = self.pending.pop().unwrap();
+        word.parse().map_err(|_| VmError::InvalidInput(word))
+    }
+}
+
+/// Registers start with arbitrary values, as in `mw.cc`, so that programs relying on
+/// uninitialised registers are caught.
+fn random_registers<W: Word>() -> [W; 8] {
+    let mut state = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_nanos() as u64).unwrap_or(1) | 1;
+    std::array::from_fn(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        W::from_i64((state % (1 << 31)) as i64)
+    })
+}
+
+/// Executes a program the way `mw.cc` does, prompting for input with `? ` and writing
+/// every output as `> value`. The word type chooses between 64-bit and arbitrary-precision
+/// arithmetic.
+pub fn run<V: Word, R: BufRead, W: Write>(program: &[Instruction], input: R, mut output: W) -> Result<Cost, VmError> {
+    let mut input = NumberReader { input, pending: Vec::new() };
+    let mut memory: HashMap<V, V> = HashMap::new();
+    let mut r: [V; 8] = random_registers();
+    let mut lr: i64 = 0;
+    let mut cost = Cost::default();
+    let mut t: u64 = 0;
+    loop {
+        let instruction = *program.get(lr as usize)
+            .filter(|_| lr >= 0)
+            .ok_or_else(|| VmError::InvalidInstruction(lr.to_string()))?;
+        match instruction {
+            Instruction::Halt => break,
+            Instruction::Read => {
+                write!(output, "? ")?;
+                output.flush()?;
+                r[0] = input.next()?;
+                cost.io += IO_COST;
+                lr += 1;
+            }
+            Instruction::Write => {
+                writeln!(output, "> {}", r[0])?;
+                cost.io += IO_COST;
+                lr += 1;
+            }
+            Instruction::Load(x) => {
+                r[0] = memory.get(&r[x]).cloned().unwrap_or_else(|| V::from_i64(0));
+                t += MEMORY_COST;
+                lr += 1;
+            }
+            Instruction::Store(x) => {
+                memory.insert(r[x].clone(), r[0].clone());
+                t += MEMORY_COST;
+                lr += 1;
+            }
+            Instruction::Add(x) => {
+                r[0] = r[0].add(&r[x]);
+                t += ARITHMETIC_COST;
+                lr += 1;
+            }
+            Instruction::Sub(x) => {
+                r[0] = r[0].sub_or_zero(&r[x]);
+                t += ARITHMETIC_COST;
+                lr += 1;
+            }
+            Instruction::Get(x) => {
+                r[0] = r[x].clone();
+                t += OTHER_COST;
+                lr += 1;
+            }
+            Instruction::Put(x) => {
+                r[x] = r[0].clone();
+                t += OTHER_COST;
+                lr += 1;
+            }
+            Instruction::Rst(x) => {
+                r[x] = V::from_i64(0);
+                t += OTHER_COST;
+                lr += 1;
+            }
+            Instruction::Inc(x) => {
+                r[x] = r[x].add(&V::from_i64(1));
+                t += OTHER_COST;
+                lr += 1;
+            }
+            Instruction::Dec(x) => {
+                if r[x].is_positive() {
+                    r[x] = r[x].sub_or_zero(&V::from_i64(1));
+                }
+                t += OTHER_COST;
+                lr += 1;
+            }
+            Instruction::Shl(x) => {
+                r[x] = r[x].shl();
+                t += OTHER_COST;
+                lr += 1;
+            }
+            Instruction::Shr(x) => {
+                r[x] = r[x].shr();
+                t += OTHER_COST;
+                lr += 1;
+            }
+            Instruction::Jump(address) => {
+                lr = address as i64;
+                t += OTHER_COST;
+            }
+            Instruction::Jpos(address) => {
+                lr = if r[0].is_positive() { address as i64 } else { lr + 1 };
+                t += OTHER_COST;
+            }
+            Instruction::Jzero(address) => {
+                lr = if r[0].is_zero() { address as i64 } else { lr + 1 };
+                t += OTHER_COST;
+            }
+            Instruction::Strk(x) => {
+                r[x] = V::from_i64(lr);
+                t += OTHER_COST;
+                lr += 1;
+            }
+            Instruction::Jumpr(x) => {
+                lr = r[x].to_address().ok_or_else(|| VmError::InvalidInstruction(r[x].to_string()))?;
+                t += OTHER_COST;
+            }
+        }
+    }
+    cost.total = t + cost.io;
+    Ok(cost)
+}
@@ -0,0 +1,224 @@
+//! Compiles the programs in `examples2023` and `tests/programs` and runs them on the built-in
+//! virtual machine. The latter are small programs checking single features of the compiler.
+//! Programs in `tests/bignum` compute values beyond 64 bits and run with `run --bignum`.
+//!
+//! Examples list their input and expected output in header comments: `# ? 20` is read by the
+//! program, `# > 167960` must be written by it, both in order. Every example runs under each
+//! combination of `--inline` and `--routines`. Cycle counts of every run are
+//! written to `examples2023-cycles.txt` in Cargo's temporary directory for integration tests.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+const INLINE_MODES: [&str; 3] = ["always", "never", "auto"];
+const ROUTINE_MODES: [&str; 3] = ["inline", "shared", "auto"];
+
+struct Example {
+    path: PathBuf,
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+}
+
+/// Directories of example programs, relative to the crate root.
+const DIRECTORIES: [&str; 2] = ["examples2023", "tests/programs"];
+
+fn examples(directory: &str) -> Vec<Example> {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join(directory);
+    let mut paths: Vec<PathBuf> = fs::read_dir(&directory)
+        .unwrap_or_else(|error| panic!("{}: {}", directory.display(), error))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "imp"))
+        .collect();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let text = fs::read_to_string(&path).unwrap();
+            let header = |prefix: &str| -> Vec<String> {
+                text.lines()
+                    .filter_map(|line| line.strip_prefix(prefix))
+                    .map(|value| value.trim().to_string())
+                    .collect()
+            };
+            Example { inputs: header("# ?"), outputs: header("# >"), path }
+        })
+        .collect()
+}
+
+fn compile(source: &Path, output: &Path, inline: &str, routines: &str) -> Result<(), String> {
+    let result = Command::new(env!("CARGO_BIN_EXE_kompilator"))
+        .arg(format!("--inline={}", inline))
+        .arg(format!("--routines={}", routines))
+        .arg(source)
+        .arg(output)
+        .output()
+        .unwrap();
+    if result.status.success() {
+        Ok(())
+    } else {
+        Err(String::from_utf8_lossy(&result.stderr).into_owned())
+    }
+}
+
+/// Runs a compiled program, returning the values it wrote and the cycles it took.
+fn run(program: &Path, inputs: &[String], bignum: bool) -> Result<(Vec<String>, u64), String> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_kompilator"))
+        .arg("run")
+        .args(bignum.then_some("--bignum"))
+        .arg(program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(inputs.join("\n").as_bytes()).unwrap();
+    let result = child.wait_with_output().unwrap();
+    if !result.status.success() {
+        return Err(String::from_utf8_lossy(&result.stderr).into_owned());
+    }
+    let stdout = String::from_utf8_lossy(&result.stdout);
+    let mut words = stdout.split_whitespace();
+    let mut outputs = Vec::new();
+    while let Some(word) = words.next() {
+        if word == ">" {
+            outputs.extend(words.next().map(str::to_string));
+        }
+    }
+    let cost = stdout
+        .split("cost: ")
+        .nth(1)
+        .and_then(|rest| rest.split(';').next())
+        .and_then(|cost| cost.parse().ok())
+        .ok_or_else(|| format!("no cost in output: {}", stdout))?;
+    Ok((outputs, cost))
+}
+
+#[test]
+fn examples_write_expected_output() {
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let mut failures = Vec::new();
+    let mut cycles = String::new();
+    let examples = DIRECTORIES.iter().flat_map(|directory| examples(directory));
+    for example in examples.filter(|example| !example.outputs.is_empty()) {
+        let name = example.path.file_stem().unwrap().to_string_lossy();
+        for inline in INLINE_MODES {
+            for routines in ROUTINE_MODES {
+                let program = directory.join(format!("{}-{}-{}.mr", name, inline, routines));
+                let result = compile(&example.path, &program, inline, routines).and_then(|()| run(&program, &example.inputs, false));
+                let mode = format!("--inline={} --routines={}", inline, routines);
+                match result {
+                    Ok((outputs, cost)) => {
+                        cycles.push_str(&format!("{} {} {} {}\n", name, inline, routines, cost));
+                        if outputs != example.outputs {
+                            failures.push(format!("{} ({}): wrote {:?}, expected {:?}", name, mode, outputs, example.outputs));
+                        }
+                    }
+                    Err(error) => failures.push(format!("{} ({}): {}", name, mode, error)),
+                }
+            }
+        }
+    }
+    fs::write(directory.join("examples2023-cycles.txt"), &cycles).unwrap();
+    print!("{}", cycles);
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn bignum_examples_write_expected_output() {
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let mut failures = Vec::new();
+    for example in examples("tests/bignum") {
+        let name = example.path.file_stem().unwrap().to_string_lossy().into_owned();
+        let program = directory.join(format!("{}-bignum.mr", name));
+        let result = compile(&example.path, &program, "auto", "auto").and_then(|()| run(&program, &example.inputs, true));
+        match result {
+            Ok((outputs, _)) if outputs != example.outputs => {
+                failures.push(format!("{}: wrote {:?}, expected {:?}", name, outputs, example.outputs));
+            }
+            Ok(_) => {}
+            Err(error) => failures.push(format!("{}: {}", name, error)),
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn every_example_compiles() {
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let failures: Vec<String> = DIRECTORIES
+        .iter()
+        .flat_map(|directory| examples(directory))
+        .filter_map(|example| {
+            let program = directory.join(example.path.with_extension("mr").file_name().unwrap());
+            compile(&example.path, &program, "auto", "auto").err().map(|error| format!("{}: {}", example.path.display(), error))
+        })
+        .collect();
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+/// Fails when generated code gets slower than recorded in `benchmarks/baseline.txt`.
+/// After an intended change in costs, refresh the file with `kompilator bench --update`.
+#[test]
+fn cycle_costs_do_not_regress() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let result = Command::new(env!("CARGO_BIN_EXE_kompilator"))
+        .arg("bench")
+        .arg(format!("--baseline={}", root.join("benchmarks/baseline.txt").display()))
+        .arg(root.join("examples2023"))
+        .output()
+        .unwrap();
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stdout));
+}
+
+/// The bench measures programs on the input listed in their headers and nothing else.
+#[test]
+fn bench_rejects_reads_beyond_the_header() {
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("bench-missing-input");
+    fs::create_dir_all(&directory).unwrap();
+    fs::write(directory.join("echo.imp"), "# ? 4\nPROGRAM IS\n    n\nIN\n    READ n;\n    READ n;\n    WRITE n;\nEND\n").unwrap();
+    let result = Command::new(env!("CARGO_BIN_EXE_kompilator"))
+        .arg("bench")
+        .arg("--update")
+        .arg(format!("--baseline={}", directory.join("baseline.txt").display()))
+        .arg(&directory)
+        .output()
+        .unwrap();
+    assert!(!result.status.success());
+    assert!(String::from_utf8_lossy(&result.stdout).contains("reads more input than its `# ?` header lists"));
+    assert!(!directory.join("baseline.txt").exists());
+}
+
+/// A multiplication loops once per bit of its smaller operand, on whichever side it is.
+#[test]
+fn multiplication_is_driven_by_the_smaller_operand() {
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let inputs = ["1000000000000000000".to_string(), "3".to_string()];
+    for (name, expression) in [("large-times-small", "a * b"), ("small-times-large", "b * a")] {
+        let source = directory.join(format!("{}.imp", name));
+        let text = format!("PROGRAM IS\n  a, b, c\nIN\n  READ a;\n  READ b;\n  c := {};\n  WRITE c;\nEND\n", expression);
+        fs::write(&source, text).unwrap();
+        for routines in ROUTINE_MODES {
+            let program = directory.join(format!("{}-{}.mr", name, routines));
+            let (outputs, cycles) = compile(&source, &program, "auto", routines)
+                .and_then(|()| run(&program, &inputs, false))
+                .unwrap();
+            assert_eq!(outputs, ["3000000000000000000"]);
+            // Two iterations take about 700 cycles with the I/O, sixty of them over 1600.
+            assert!(cycles < 800, "{} (--routines={}) took {} cycles", expression, routines, cycles);
+        }
+    }
+}
+
+/// A command replaced while recovering from a syntax error loses its uses of variables, so
+/// warnings about unused variables would be wrong then.
+#[test]
+fn syntax_errors_silence_warnings() {
+    let directory = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    let source = directory.join("syntax-error.imp");
+    fs::write(&source, "PROGRAM IS\n  a, b\nIN\n  READ a;\n  b := a + ;\n  WRITE b;\nEND\n").unwrap();
+    let error = compile(&source, &directory.join("syntax-error.mr"), "auto", "auto").unwrap_err();
+    assert!(error.contains("syntax error"), "{}", error);
+    assert!(!error.contains("never used"), "{}", error);
+}